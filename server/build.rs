@@ -0,0 +1,7 @@
+// include_dir can't tell cargo which files it embedded, so rebuild whenever the tailwind output changes
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if std::env::var_os("CARGO_FEATURE_EMBED_STATIC").is_some() {
+        println!("cargo:rerun-if-changed=../static");
+    }
+}
@@ -0,0 +1,16 @@
+CREATE OR REPLACE FUNCTION notify_user_preferences_changed()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('user_preferences_changed', OLD.preference_key);
+    ELSE
+        PERFORM pg_notify('user_preferences_changed', NEW.preference_key);
+    END IF;
+    RETURN NULL;
+END;
+$$ language 'plpgsql';
+
+CREATE TRIGGER notify_user_preferences_changed
+    AFTER INSERT OR UPDATE OR DELETE ON user_preferences
+    FOR EACH ROW
+    EXECUTE FUNCTION notify_user_preferences_changed();
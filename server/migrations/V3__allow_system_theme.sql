@@ -0,0 +1,4 @@
+ALTER TABLE user_preferences DROP CONSTRAINT user_preferences_theme_check;
+
+ALTER TABLE user_preferences
+    ADD CONSTRAINT user_preferences_theme_check CHECK (theme IN ('light', 'dark', 'system'));
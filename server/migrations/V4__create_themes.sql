@@ -0,0 +1,12 @@
+-- the rows come from themes.toml alone, database::sync_themes upserts them on every startup.
+-- the foreign key starts out NOT VALID so existing preferences aren't checked against the still
+-- empty table, sync_themes validates it once the rows are there
+CREATE TABLE themes (
+    name VARCHAR(32) PRIMARY KEY,
+    label VARCHAR(64) NOT NULL
+);
+
+ALTER TABLE user_preferences DROP CONSTRAINT user_preferences_theme_check;
+ALTER TABLE user_preferences ALTER COLUMN theme TYPE VARCHAR(32);
+ALTER TABLE user_preferences
+    ADD CONSTRAINT user_preferences_theme_fkey FOREIGN KEY (theme) REFERENCES themes(name) NOT VALID;
@@ -0,0 +1,34 @@
+CREATE TABLE custom_themes (
+    id SERIAL PRIMARY KEY,
+    -- the owner's "user_123" preference key, removed along with it
+    preference_key VARCHAR(64) NOT NULL REFERENCES user_preferences(preference_key) ON DELETE CASCADE,
+    name VARCHAR(64) NOT NULL,
+    base_theme VARCHAR(32) NOT NULL REFERENCES themes(name),
+    variables JSONB NOT NULL, -- css variable name => oklch() value, validated by the server
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    UNIQUE (preference_key, name)
+);
+
+CREATE TRIGGER update_custom_themes_updated_at
+    BEFORE UPDATE ON custom_themes
+    FOR EACH ROW
+    EXECUTE FUNCTION update_updated_at_column();
+
+-- pages cache the active palette per preference key, so edits have to reach every instance.
+-- deletes already do through ON DELETE SET NULL on user_preferences
+CREATE OR REPLACE FUNCTION notify_custom_themes_changed()
+RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('user_preferences_changed', NEW.preference_key);
+    RETURN NULL;
+END;
+$$ language 'plpgsql';
+
+CREATE TRIGGER notify_custom_themes_changed
+    AFTER UPDATE ON custom_themes
+    FOR EACH ROW
+    EXECUTE FUNCTION notify_custom_themes_changed();
+
+ALTER TABLE user_preferences
+    ADD COLUMN custom_theme_id INTEGER REFERENCES custom_themes(id) ON DELETE SET NULL;
@@ -0,0 +1,6 @@
+-- rows can now exist for other preferences, a null theme means none was chosen
+ALTER TABLE user_preferences ALTER COLUMN theme DROP NOT NULL;
+ALTER TABLE user_preferences ALTER COLUMN theme DROP DEFAULT;
+
+-- every preference except the theme, keyed by the names in preference_schema.rs
+ALTER TABLE user_preferences ADD COLUMN settings JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,10 @@
+-- token buckets for RATE_LIMIT_STORE=postgres. losing them in a crash only resets the limits,
+-- so the table skips the wal
+CREATE UNLOGGED TABLE rate_limit_buckets (
+    bucket_key VARCHAR(128) PRIMARY KEY, -- "write:ip:1.2.3.4", "write:key:anon_uuid", "mint:ip:1.2.3.4"
+    tokens DOUBLE PRECISION NOT NULL,
+    allowed BOOLEAN NOT NULL, -- outcome of the last take, read back by the same statement
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_rate_limit_buckets_updated_at ON rate_limit_buckets(updated_at);
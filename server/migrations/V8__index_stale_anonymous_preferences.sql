@@ -0,0 +1,6 @@
+-- the cleanup job looks for anonymous rows by updated_at, nothing ever filtered by created_at
+DROP INDEX IF EXISTS idx_user_preferences_created_at;
+
+CREATE INDEX idx_user_preferences_anon_updated_at
+    ON user_preferences(updated_at)
+    WHERE preference_key LIKE 'anon\_%';
@@ -0,0 +1,227 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+    Router,
+};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+#[cfg(not(feature = "embed-static"))]
+use std::{env, fs};
+#[cfg(not(feature = "embed-static"))]
+use tower_http::services::ServeDir;
+
+#[cfg(not(feature = "embed-static"))]
+const DEFAULT_ASSET_MANIFEST: &str = "static/manifest.json";
+
+// a new build changes the name, so a hashed url never needs revalidating
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+// unhashed files can change under the same url, browsers check the etag before reusing them
+const REVALIDATE: &str = "public, no-cache";
+
+#[cfg(feature = "embed-static")]
+mod embedded;
+
+// written by `cargo run -p tailwind`, read once at startup like the rest of static/
+static MANIFEST: Lazy<HashMap<String, AssetEntry>> = Lazy::new(|| {
+    let (source, json) = read_manifest();
+    let manifest = json.and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()));
+
+    manifest.unwrap_or_else(|e| {
+        eprintln!("WARNING: asset manifest {} not loaded, serving assets unhashed: {}", source, e);
+        HashMap::new()
+    })
+});
+
+#[cfg(not(feature = "embed-static"))]
+fn read_manifest() -> (String, Result<String, String>) {
+    let path = env::var("ASSET_MANIFEST").unwrap_or_else(|_| DEFAULT_ASSET_MANIFEST.to_string());
+    let json = fs::read_to_string(&path).map_err(|e| e.to_string());
+    (path, json)
+}
+
+// the embedded manifest always matches the embedded files, ASSET_MANIFEST doesn't apply
+#[cfg(feature = "embed-static")]
+fn read_manifest() -> (String, Result<String, String>) {
+    let json = embedded::manifest().ok_or_else(|| "not embedded".to_string());
+    ("(embedded)".to_string(), json)
+}
+
+#[derive(Debug, Deserialize)]
+struct AssetEntry {
+    file: String,
+    integrity: String,
+}
+
+// names templates asked for that the manifest lacks, so each one is reported once
+static MISSING: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+// the fallbacks below keep pages rendering, but the url 404s and the integrity is empty,
+// so a stale static/ has to show up in the log
+fn entry(name: &str) -> Option<&'static AssetEntry> {
+    let entry = MANIFEST.get(name);
+    if entry.is_none() && !MANIFEST.is_empty() && MISSING.lock().unwrap().insert(name.to_string()) {
+        eprintln!("WARNING: asset \"{}\" is not in the manifest, rebuild static/ with `cargo run -p tailwind`", name);
+    }
+    entry
+}
+
+// the url of a logical asset like "computed.css"
+pub fn url(name: &str) -> String {
+    match entry(name) {
+        Some(entry) => format!("/static/{}", entry.file),
+        None => format!("/static/{}", name),
+    }
+}
+
+// sha384 for integrity attributes, empty for unknown assets so the attribute is simply ignored
+pub fn integrity(name: &str) -> &'static str {
+    entry(name).map(|entry| entry.integrity.as_str()).unwrap_or_default()
+}
+
+// urls of the woff2 subsets the tailwind build makes from the fonts in assets.toml, sorted
+pub fn fonts() -> Vec<String> {
+    let mut fonts: Vec<String> = MANIFEST.keys().filter(|name| name.ends_with(".woff2")).map(|name| url(name)).collect();
+    fonts.sort();
+    fonts
+}
+
+// `{{ asset("computed.css") }}` and `{{ asset_integrity("htmx.js") }}` in every template,
+// askama resolves bare function calls as methods on the template
+pub trait AssetHelpers {
+    fn asset(&self, name: &str) -> String {
+        url(name)
+    }
+
+    fn asset_integrity(&self, name: &str) -> &'static str {
+        integrity(name)
+    }
+
+    // base.html preloads these, a font added to assets.toml shows up without touching the template
+    fn preload_fonts(&self) -> Vec<String> {
+        fonts()
+    }
+}
+
+impl<T: askama::Template> AssetHelpers for T {}
+
+// the `tailwind` build names fingerprinted files {stem}.{8 hex digits}.{ext}
+fn is_fingerprinted(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let mut parts = name.rsplit('.');
+    let (Some(_), Some(hash), Some(stem)) = (parts.next(), parts.next(), parts.next()) else {
+        return false;
+    };
+
+    !stem.is_empty() && hash.len() == 8 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+// ServeDir only does Last-Modified, derive an etag from it, the size and the encoding so the
+// .br and .gz siblings never share one with the plain file
+fn entity_tag(headers: &HeaderMap) -> Option<HeaderValue> {
+    let last_modified = headers.get(header::LAST_MODIFIED)?;
+    let length = headers.get(header::CONTENT_LENGTH)?;
+    let encoding = headers.get(header::CONTENT_ENCODING).map(HeaderValue::as_bytes).unwrap_or_default();
+
+    let digest = Sha256::new()
+        .chain_update(last_modified.as_bytes())
+        .chain_update(b"|")
+        .chain_update(length.as_bytes())
+        .chain_update(b"|")
+        .chain_update(encoding)
+        .finalize();
+    let hex = format!("{:x}", digest);
+    HeaderValue::from_str(&format!("W/\"{}\"", &hex[..16])).ok()
+}
+
+// weak comparison, If-None-Match is a list of tags or *
+fn matches_entity_tag(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag.to_str().unwrap_or_default());
+
+    if_none_match.split(',').any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+// cache-control, vary and etag for /static, ServeDir picks the precompressed sibling
+pub async fn cache_headers(req: Request, next: Next) -> Response {
+    let immutable = is_fingerprinted(req.uri().path());
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+
+    let mut response = next.run(req).await;
+    let status = response.status();
+    if status != StatusCode::OK && status != StatusCode::NOT_MODIFIED {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(if immutable { IMMUTABLE } else { REVALIDATE }));
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+    let Some(etag) = entity_tag(headers) else {
+        return response;
+    };
+    headers.insert(header::ETAG, etag.clone());
+
+    if if_none_match.is_some_and(|if_none_match| matches_entity_tag(&if_none_match, &etag)) {
+        let mut not_modified = Response::new(Body::empty());
+        *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+        for name in [header::CACHE_CONTROL, header::VARY, header::ETAG, header::LAST_MODIFIED] {
+            if let Some(value) = response.headers().get(&name) {
+                not_modified.headers_mut().insert(name, value.clone());
+            }
+        }
+        return not_modified;
+    }
+
+    response
+}
+
+// /static from disk, or from the binary with the `embed-static` feature
+pub fn router() -> Router {
+    #[cfg(feature = "embed-static")]
+    let router = Router::new().fallback(embedded::serve);
+    // fingerprinted files with their .br and .gz siblings from the `tailwind` build
+    #[cfg(not(feature = "embed-static"))]
+    let router = Router::new().fallback_service(ServeDir::new("static").precompressed_br().precompressed_gzip());
+
+    router.layer(axum::middleware::from_fn(cache_headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprinted_names() {
+        assert!(is_fingerprinted("/computed.12acdb7a.css"));
+        assert!(is_fingerprinted("/fonts/fira_code.9aa64379.ttf"));
+        assert!(!is_fingerprinted("/favicon.svg"));
+        assert!(!is_fingerprinted("/manifest.json"));
+        assert!(!is_fingerprinted("/.12acdb7a.css"));
+        assert!(!is_fingerprinted("/computed.12ACDB7A.css"));
+    }
+
+    #[test]
+    fn test_entity_tag_varies_with_encoding() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::LAST_MODIFIED, HeaderValue::from_static("Sun, 19 Oct 2026 10:00:00 GMT"));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("1024"));
+        let plain = entity_tag(&headers).unwrap();
+
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("br"));
+        let brotli = entity_tag(&headers).unwrap();
+
+        assert_ne!(plain, brotli);
+        assert!(matches_entity_tag(&HeaderValue::from_str(&format!("\"x\", {}", brotli.to_str().unwrap())).unwrap(), &brotli));
+        assert!(matches_entity_tag(&HeaderValue::from_static("*"), &plain));
+        assert!(!matches_entity_tag(&plain, &brotli));
+    }
+}
@@ -0,0 +1,108 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use include_dir::{include_dir, Dir, File};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// the whole build output, so the release binary runs from any directory
+static STATIC: Dir<'static> = include_dir!("$CARGO_MANIFEST_DIR/../static");
+
+// hashed once at startup instead of per request
+static FILES: Lazy<HashMap<String, EmbeddedFile>> = Lazy::new(|| {
+    let mut files = HashMap::new();
+    let mut directories = vec![&STATIC];
+    while let Some(directory) = directories.pop() {
+        directories.extend(directory.dirs());
+        for file in directory.files() {
+            let path = file.path().to_string_lossy().replace('\\', "/");
+            if path.ends_with(".br") || path.ends_with(".gz") {
+                continue;
+            }
+
+            let content_type = mime_guess::from_path(&path).first_or_octet_stream();
+            files.insert(path.clone(), EmbeddedFile {
+                content_type: HeaderValue::from_str(content_type.as_ref()).unwrap_or(HeaderValue::from_static("application/octet-stream")),
+                identity: Variant::new(file),
+                br: STATIC.get_file(format!("{}.br", path)).map(Variant::new),
+                gzip: STATIC.get_file(format!("{}.gz", path)).map(Variant::new),
+            });
+        }
+    }
+    files
+});
+
+struct EmbeddedFile {
+    content_type: HeaderValue,
+    identity: Variant,
+    br: Option<Variant>,
+    gzip: Option<Variant>,
+}
+
+struct Variant {
+    content: &'static [u8],
+    etag: HeaderValue,
+}
+
+impl Variant {
+    fn new(file: &'static File<'static>) -> Self {
+        let hex = format!("{:x}", Sha256::digest(file.contents()));
+        Variant {
+            content: file.contents(),
+            etag: HeaderValue::from_str(&format!("\"{}\"", &hex[..16])).expect("hex is a valid etag"),
+        }
+    }
+}
+
+pub fn manifest() -> Option<String> {
+    STATIC.get_file("manifest.json")?.contents_utf8().map(str::to_string)
+}
+
+// coding names from Accept-Encoding, leaving out the ones refused with q=0
+fn accepts(headers: &HeaderMap, coding: &str) -> bool {
+    let Some(accept_encoding) = headers.get(header::ACCEPT_ENCODING).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+
+    accept_encoding.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|param| matches!(param, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+        name.eq_ignore_ascii_case(coding) && !refused
+    })
+}
+
+// same responses as ServeDir with precompression, minus ranges
+pub async fn serve(uri: Uri, headers: HeaderMap) -> Response {
+    let Some(file) = FILES.get(uri.path().trim_start_matches('/')) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let (variant, encoding) = match (&file.br, &file.gzip) {
+        (Some(br), _) if accepts(&headers, "br") => (br, Some("br")),
+        (_, Some(gzip)) if accepts(&headers, "gzip") => (gzip, Some("gzip")),
+        _ => (&file.identity, None),
+    };
+
+    let not_modified = headers.get(header::IF_NONE_MATCH)
+        .is_some_and(|if_none_match| super::matches_entity_tag(if_none_match, &variant.etag));
+
+    let mut response = if not_modified {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        response
+    } else {
+        let mut response = Response::new(Body::from(variant.content));
+        response.headers_mut().insert(header::CONTENT_TYPE, file.content_type.clone());
+        if let Some(encoding) = encoding {
+            response.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        response
+    };
+
+    response.headers_mut().insert(header::ETAG, variant.etag.clone());
+    response
+}
@@ -0,0 +1,449 @@
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::custom_themes::{CustomTheme, CustomThemeRepository, PaletteRequest, SharedCustomThemes};
+use crate::preference_schema::PreferenceMap;
+use crate::preferences::{PreferencesRepository, SharedPreferences};
+
+const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+const DEFAULT_CACHE_TTL_SECS: u64 = 300;
+const STATS_INTERVAL: Duration = Duration::from_secs(300);
+
+// PREFERENCE_CACHE_CAPACITY and PREFERENCE_CACHE_TTL_SECS override the defaults, for both caches
+fn limits_from_env() -> (usize, Duration) {
+    let capacity = env::var("PREFERENCE_CACHE_CAPACITY")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_CACHE_CAPACITY);
+    let ttl = env::var("PREFERENCE_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_CACHE_TTL_SECS);
+
+    (capacity, Duration::from_secs(ttl))
+}
+
+struct Entry<V> {
+    value: V,
+    expires_at: Instant,
+    tick: u64,
+}
+
+// misses reading a key right now. a change to the key bumps the generation, and a read that
+// started under an older one may have seen the value from before the change
+struct Pending {
+    generation: u64,
+    readers: usize,
+}
+
+// bounded ttl cache that evicts the least recently used key once full
+struct LruTtl<V> {
+    entries: HashMap<String, Entry<V>>,
+    // recency order, oldest tick first
+    order: BTreeMap<u64, String>,
+    next_tick: u64,
+    capacity: usize,
+    ttl: Duration,
+    // only keys with a read in flight, so this stays as small as the number of concurrent misses
+    pending: HashMap<String, Pending>,
+}
+
+impl<V: Clone> LruTtl<V> {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_tick: 0,
+            capacity: capacity.max(1),
+            ttl,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn bump(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+
+    fn get(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.get(key)?;
+        if entry.expires_at <= Instant::now() {
+            self.remove(key);
+            return None;
+        }
+
+        let old_tick = entry.tick;
+        let tick = self.bump();
+        let entry = self.entries.get_mut(key)?;
+        entry.tick = tick;
+        let value = entry.value.clone();
+
+        self.order.remove(&old_tick);
+        self.order.insert(tick, key.to_string());
+        Some(value)
+    }
+
+    // returns whether an older entry had to be evicted to make room
+    fn insert(&mut self, key: &str, value: V) -> bool {
+        self.remove(key);
+
+        let evicted = self.entries.len() >= self.capacity && self.evict_oldest();
+
+        let tick = self.bump();
+        self.order.insert(tick, key.to_string());
+        self.entries.insert(key.to_string(), Entry {
+            value,
+            expires_at: Instant::now() + self.ttl,
+            tick,
+        });
+
+        evicted
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        match self.order.pop_first() {
+            Some((_, oldest)) => self.entries.remove(&oldest).is_some(),
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+        }
+    }
+
+    // a miss is about to read the store, returns the generation to hand to finish_read
+    fn begin_read(&mut self, key: &str) -> u64 {
+        let pending = self.pending.entry(key.to_string()).or_insert(Pending { generation: 0, readers: 0 });
+        pending.readers += 1;
+        pending.generation
+    }
+
+    // caches what the read returned, unless the key changed while it was reading.
+    // the notification for that change may have arrived before the read finished.
+    // `value` is None when the read failed. returns whether an entry had to be evicted
+    fn finish_read(&mut self, key: &str, generation: u64, value: Option<V>) -> bool {
+        let Some(pending) = self.pending.get_mut(key) else {
+            return false;
+        };
+        let current = pending.generation == generation;
+        pending.readers -= 1;
+        if pending.readers == 0 {
+            self.pending.remove(key);
+        }
+
+        match value {
+            Some(value) if current => self.insert(key, value),
+            _ => false,
+        }
+    }
+
+    fn outdate(&mut self, key: &str) {
+        if let Some(pending) = self.pending.get_mut(key) {
+            pending.generation += 1;
+        }
+    }
+
+    fn invalidate(&mut self, key: &str) {
+        self.outdate(key);
+        self.remove(key);
+    }
+
+    fn clear(&mut self) {
+        for pending in self.pending.values_mut() {
+            pending.generation += 1;
+        }
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[derive(Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+// sits in front of another repository, reads are cached and writes go through to both
+pub struct CachedPreferencesRepository {
+    inner: SharedPreferences,
+    // None is a negative entry for visitors without a stored preference
+    entries: Mutex<LruTtl<Option<String>>>,
+    stats: CacheStats,
+}
+
+impl CachedPreferencesRepository {
+    pub fn new(inner: SharedPreferences, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            inner,
+            entries: Mutex::new(LruTtl::new(capacity, ttl)),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn from_env(inner: SharedPreferences) -> Self {
+        let (capacity, ttl) = limits_from_env();
+        Self::new(inner, capacity, ttl)
+    }
+
+    pub fn invalidate(&self, preference_key: &str) {
+        self.entries.lock().unwrap().invalidate(preference_key);
+        self.stats.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // used when invalidations may have been missed, e.g. the listener reconnected
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn summary(&self) -> String {
+        let entries = self.entries.lock().unwrap().entries.len();
+        format!(
+            "{} hits, {} misses, {} evictions, {} invalidations, {} entries",
+            self.stats.hits.load(Ordering::Relaxed),
+            self.stats.misses.load(Ordering::Relaxed),
+            self.stats.evictions.load(Ordering::Relaxed),
+            self.stats.invalidations.load(Ordering::Relaxed),
+            entries
+        )
+    }
+
+    // write-through, also outdates whatever a concurrent miss of the same key is reading
+    fn store(&self, preference_key: &str, theme: Option<String>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.outdate(preference_key);
+        if entries.insert(preference_key, theme) {
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn fill(&self, preference_key: &str, generation: u64, theme: Option<Option<String>>) {
+        if self.entries.lock().unwrap().finish_read(preference_key, generation, theme) {
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[async_trait]
+impl PreferencesRepository for CachedPreferencesRepository {
+    async fn get_theme(&self, preference_key: &str) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let generation = {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(theme) = entries.get(preference_key) {
+                self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(theme);
+            }
+            entries.begin_read(preference_key)
+        };
+
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        let theme = self.inner.get_theme(preference_key).await;
+        self.fill(preference_key, generation, theme.as_ref().ok().cloned());
+
+        theme
+    }
+
+    async fn save_theme(&self, preference_key: &str, theme: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Err(e) = self.inner.save_theme(preference_key, theme).await {
+            // the stored value is unknown now, so drop rather than keep a stale entry
+            self.entries.lock().unwrap().invalidate(preference_key);
+            return Err(e);
+        }
+
+        self.store(preference_key, Some(theme.to_string()));
+        Ok(())
+    }
+
+    // only the theme is read on every page, the other preferences go straight to the store
+    async fn get_preferences(&self, preference_key: &str) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.get_preferences(preference_key).await
+    }
+
+    async fn update_preferences(&self, preference_key: &str, patch: &PreferenceMap) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>> {
+        let stored = match self.inner.update_preferences(preference_key, patch).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                self.entries.lock().unwrap().invalidate(preference_key);
+                return Err(e);
+            }
+        };
+
+        let theme = stored.get("theme").and_then(|theme| theme.as_str()).map(str::to_string);
+        self.store(preference_key, theme);
+        Ok(stored)
+    }
+}
+
+// caches the active palette every page render looks up, everything else goes to the store.
+// writes drop the owner's entry here, other instances hear about them through the V5 triggers
+pub struct CachedCustomThemeRepository {
+    inner: SharedCustomThemes,
+    active: Mutex<LruTtl<Option<CustomTheme>>>,
+}
+
+impl CachedCustomThemeRepository {
+    pub fn new(inner: SharedCustomThemes, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            inner,
+            active: Mutex::new(LruTtl::new(capacity, ttl)),
+        }
+    }
+
+    pub fn from_env(inner: SharedCustomThemes) -> Self {
+        let (capacity, ttl) = limits_from_env();
+        Self::new(inner, capacity, ttl)
+    }
+
+    pub fn invalidate(&self, preference_key: &str) {
+        self.active.lock().unwrap().invalidate(preference_key);
+    }
+
+    pub fn clear(&self) {
+        self.active.lock().unwrap().clear();
+    }
+}
+
+#[async_trait]
+impl CustomThemeRepository for CachedCustomThemeRepository {
+    async fn list(&self, preference_key: &str) -> Result<Vec<CustomTheme>, Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.list(preference_key).await
+    }
+
+    async fn create(&self, preference_key: &str, palette: &PaletteRequest) -> Result<CustomTheme, Box<dyn std::error::Error + Send + Sync>> {
+        let created = self.inner.create(preference_key, palette).await;
+        self.invalidate(preference_key);
+        created
+    }
+
+    async fn update(&self, preference_key: &str, id: i32, palette: &PaletteRequest) -> Result<Option<CustomTheme>, Box<dyn std::error::Error + Send + Sync>> {
+        let updated = self.inner.update(preference_key, id, palette).await;
+        self.invalidate(preference_key);
+        updated
+    }
+
+    async fn delete(&self, preference_key: &str, id: i32) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let deleted = self.inner.delete(preference_key, id).await;
+        self.invalidate(preference_key);
+        deleted
+    }
+
+    async fn get(&self, preference_key: &str, id: i32) -> Result<Option<CustomTheme>, Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.get(preference_key, id).await
+    }
+
+    async fn set_active(&self, preference_key: &str, id: Option<i32>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let result = self.inner.set_active(preference_key, id).await;
+        self.invalidate(preference_key);
+        result
+    }
+
+    async fn active(&self, preference_key: &str) -> Result<Option<CustomTheme>, Box<dyn std::error::Error + Send + Sync>> {
+        let generation = {
+            let mut active = self.active.lock().unwrap();
+            if let Some(theme) = active.get(preference_key) {
+                return Ok(theme);
+            }
+            active.begin_read(preference_key)
+        };
+
+        let theme = self.inner.active(preference_key).await;
+        self.active.lock().unwrap().finish_read(preference_key, generation, theme.as_ref().ok().cloned());
+        theme
+    }
+}
+
+// periodically print cache counters so hit rates show up in the journal
+pub fn spawn_stats_logger(cache: Arc<CachedPreferencesRepository>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STATS_INTERVAL);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            println!("preference cache: {}", cache.summary());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preferences::InMemoryPreferencesRepository;
+
+    fn cached(capacity: usize, ttl: Duration) -> CachedPreferencesRepository {
+        CachedPreferencesRepository::new(Arc::new(InMemoryPreferencesRepository::default()), capacity, ttl)
+    }
+
+    #[tokio::test]
+    async fn test_negative_entries_are_cached() {
+        let cache = cached(10, Duration::from_secs(60));
+
+        assert_eq!(cache.get_theme("anon_a").await.unwrap(), None);
+        assert_eq!(cache.get_theme("anon_a").await.unwrap(), None);
+
+        assert_eq!(cache.stats.misses.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.stats.hits.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_write_through_and_invalidate() {
+        let cache = cached(10, Duration::from_secs(60));
+
+        cache.save_theme("anon_a", "light").await.unwrap();
+        assert_eq!(cache.get_theme("anon_a").await.unwrap().as_deref(), Some("light"));
+        assert_eq!(cache.stats.misses.load(Ordering::Relaxed), 0);
+
+        cache.invalidate("anon_a");
+        assert_eq!(cache.get_theme("anon_a").await.unwrap().as_deref(), Some("light"));
+        assert_eq!(cache.stats.misses.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_fill_after_invalidation_is_dropped() {
+        let cache = cached(10, Duration::from_secs(60));
+
+        // misses read "dark", then the change notification for anon_a arrived before they stored it
+        let outdated = cache.entries.lock().unwrap().begin_read("anon_a");
+        let unrelated = cache.entries.lock().unwrap().begin_read("anon_b");
+        cache.invalidate("anon_a");
+        cache.fill("anon_a", outdated, Some(Some("dark".to_string())));
+        cache.fill("anon_b", unrelated, Some(Some("dark".to_string())));
+        assert!(cache.entries.lock().unwrap().get("anon_a").is_none());
+        assert_eq!(cache.entries.lock().unwrap().get("anon_b"), Some(Some("dark".to_string())));
+
+        let generation = cache.entries.lock().unwrap().begin_read("anon_a");
+        cache.fill("anon_a", generation, Some(Some("light".to_string())));
+        assert_eq!(cache.entries.lock().unwrap().get("anon_a"), Some(Some("light".to_string())));
+        assert!(cache.entries.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let mut lru: LruTtl<Option<String>> = LruTtl::new(2, Duration::from_secs(60));
+
+        lru.insert("a", None);
+        lru.insert("b", None);
+        lru.get("a");
+        assert!(lru.insert("c", None));
+
+        assert!(lru.get("a").is_some());
+        assert!(lru.get("b").is_none());
+        assert!(lru.get("c").is_some());
+    }
+
+    #[test]
+    fn test_expired_entries_are_dropped() {
+        let mut lru = LruTtl::new(2, Duration::ZERO);
+
+        lru.insert("a", Some("dark".to_string()));
+        assert!(lru.get("a").is_none());
+        assert!(lru.entries.is_empty());
+    }
+}
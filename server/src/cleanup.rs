@@ -0,0 +1,109 @@
+use std::env;
+use std::time::{Duration, Instant};
+
+use crate::database::{self, DbPool};
+
+const DEFAULT_RETENTION_DAYS: i32 = 365;
+const DEFAULT_CLEANUP_INTERVAL_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_CLEANUP_BATCH_SIZE: i64 = 1000;
+
+// "clean" in hex, only one instance sweeps at a time
+const CLEANUP_LOCK_KEY: i64 = 0x636c65616e;
+
+// anonymous rows untouched for longer than the retention, oldest first. SKIP LOCKED leaves rows
+// a visitor is writing right now for the next run, the partial index from V8 covers the filter
+const DELETE_STALE_BATCH: &str = "DELETE FROM user_preferences WHERE preference_key IN (
+         SELECT preference_key FROM user_preferences
+         WHERE preference_key LIKE 'anon\\_%' AND updated_at < NOW() - make_interval(days => $1)
+         ORDER BY updated_at
+         LIMIT $2
+         FOR UPDATE SKIP LOCKED
+     )";
+
+#[derive(Debug, Clone, Copy)]
+pub struct CleanupConfig {
+    // anonymous tokens live 365 days and every write reissues them, so older rows are unreachable
+    pub retention_days: i32,
+    pub interval: Duration,
+    pub batch_size: i64,
+}
+
+impl CleanupConfig {
+    pub fn from_env() -> Self {
+        let retention_days = env::var("PREFERENCE_RETENTION_DAYS")
+            .ok()
+            .and_then(|days| days.parse().ok())
+            .filter(|days| *days > 0)
+            .unwrap_or(DEFAULT_RETENTION_DAYS);
+        let interval = env::var("PREFERENCE_CLEANUP_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(DEFAULT_CLEANUP_INTERVAL_SECS);
+        let batch_size = env::var("PREFERENCE_CLEANUP_BATCH_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_CLEANUP_BATCH_SIZE);
+
+        Self { retention_days, interval: Duration::from_secs(interval), batch_size }
+    }
+}
+
+// deletes stale anonymous rows in batches, returns how many, or None when another instance holds the lock
+pub async fn run_once(db_pool: &DbPool, config: CleanupConfig) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+    let conn = database::connect_dedicated(&database::instance_id()).await?;
+    if !database::try_advisory_lock(&conn, CLEANUP_LOCK_KEY).await? {
+        return Ok(None);
+    }
+
+    let start = Instant::now();
+    let result = delete_in_batches(db_pool, config).await;
+
+    // release even after a failed run, a failed unlock is covered by closing the connection
+    if let Err(e) = database::release_advisory_lock(&conn, CLEANUP_LOCK_KEY).await {
+        eprintln!("releasing cleanup lock failed, closing the connection instead: {}", e);
+    }
+    drop(conn);
+
+    let (deleted, batches) = result?;
+    println!(
+        "preference cleanup: deleted {} stale anonymous rows in {} batches ({:?})",
+        deleted, batches, start.elapsed()
+    );
+    Ok(Some(deleted))
+}
+
+// every batch is its own statement, so no transaction holds row locks for the whole sweep
+async fn delete_in_batches(db_pool: &DbPool, config: CleanupConfig) -> Result<(u64, u32), Box<dyn std::error::Error + Send + Sync>> {
+    let conn = db_pool.get().await?;
+    let statement = conn.prepare_cached(DELETE_STALE_BATCH).await?;
+
+    let mut deleted = 0;
+    let mut batches = 0;
+    loop {
+        let count = conn.execute(&statement, &[&config.retention_days, &config.batch_size]).await?;
+        deleted += count;
+        batches += 1;
+
+        if count < config.batch_size as u64 {
+            return Ok((deleted, batches));
+        }
+    }
+}
+
+pub fn spawn(db_pool: DbPool, config: CleanupConfig) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            match run_once(&db_pool, config).await {
+                Ok(Some(_)) => {}
+                Ok(None) => println!("preference cleanup: running on another instance, skipped"),
+                Err(e) => eprintln!("preference cleanup failed: {}", e),
+            }
+        }
+    });
+}
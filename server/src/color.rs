@@ -0,0 +1,99 @@
+// just enough colour math to validate user palettes: parsing css oklch() values and wcag contrast
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Oklch {
+    pub lightness: f64,
+    pub chroma: f64,
+    pub hue: f64,
+    pub alpha: f64,
+}
+
+const MAX_CHROMA: f64 = 0.5;
+
+impl Oklch {
+    // accepts `oklch(L C H)` and `oklch(L C H / A)`, lightness and alpha may be percentages
+    pub fn parse(value: &str) -> Option<Oklch> {
+        let inner = value.trim().strip_prefix("oklch(")?.strip_suffix(')')?;
+        let (channels, alpha) = match inner.split_once('/') {
+            Some((channels, alpha)) => (channels, Some(alpha)),
+            None => (inner, None),
+        };
+
+        let mut parts = channels.split_whitespace();
+        let lightness = parse_fraction(parts.next()?)?;
+        let chroma: f64 = parts.next()?.parse().ok()?;
+        let hue: f64 = parts.next()?.trim_end_matches("deg").parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let alpha = match alpha {
+            Some(alpha) => parse_fraction(alpha.trim())?,
+            None => 1.0,
+        };
+
+        let in_range = (0.0..=1.0).contains(&lightness)
+            && (0.0..=MAX_CHROMA).contains(&chroma)
+            && hue.is_finite()
+            && (0.0..=1.0).contains(&alpha);
+
+        in_range.then_some(Oklch { lightness, chroma, hue, alpha })
+    }
+
+    // relative luminance as defined by wcag, computed from linear srgb clamped to the gamut
+    pub fn relative_luminance(&self) -> f64 {
+        let (a, b) = {
+            let hue = self.hue.to_radians();
+            (self.chroma * hue.cos(), self.chroma * hue.sin())
+        };
+
+        let l = (self.lightness + 0.396_337_777_4 * a + 0.215_803_757_3 * b).powi(3);
+        let m = (self.lightness - 0.105_561_345_8 * a - 0.063_854_172_8 * b).powi(3);
+        let s = (self.lightness - 0.089_484_177_5 * a - 1.291_485_548_0 * b).powi(3);
+
+        let red = 4.076_741_662_1 * l - 3.307_711_591_3 * m + 0.230_969_929_2 * s;
+        let green = -1.268_438_004_6 * l + 2.609_757_401_1 * m - 0.341_319_396_5 * s;
+        let blue = -0.004_196_086_3 * l - 0.703_418_614_7 * m + 1.707_614_701_0 * s;
+
+        0.2126 * red.clamp(0.0, 1.0) + 0.7152 * green.clamp(0.0, 1.0) + 0.0722 * blue.clamp(0.0, 1.0)
+    }
+}
+
+fn parse_fraction(value: &str) -> Option<f64> {
+    match value.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().ok().map(|p| p / 100.0),
+        None => value.parse().ok(),
+    }
+}
+
+pub fn contrast_ratio(first: &Oklch, second: &Oklch) -> f64 {
+    let (a, b) = (first.relative_luminance(), second.relative_luminance());
+    (a.max(b) + 0.05) / (a.min(b) + 0.05)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_oklch() {
+        let color = Oklch::parse("oklch(0.22 0.025 260 / 60%)").unwrap();
+        assert_eq!(color.lightness, 0.22);
+        assert_eq!(color.alpha, 0.6);
+
+        assert_eq!(Oklch::parse("oklch(50% 0.1 120deg)").unwrap().lightness, 0.5);
+        assert!(Oklch::parse("oklch(1.5 0.1 120)").is_none());
+        assert!(Oklch::parse("oklch(0.5 0.1)").is_none());
+        assert!(Oklch::parse("rgb(0 0 0)").is_none());
+        assert!(Oklch::parse("oklch(0.5 0.1 120); color: red").is_none());
+    }
+
+    #[test]
+    fn test_contrast_extremes() {
+        let black = Oklch::parse("oklch(0 0 0)").unwrap();
+        let white = Oklch::parse("oklch(1 0 0)").unwrap();
+
+        assert!((contrast_ratio(&black, &white) - 21.0).abs() < 0.05);
+        assert!((contrast_ratio(&white, &white) - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,211 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+use std::env;
+use std::fmt::Write;
+use tower_cookies::Cookies;
+
+use crate::error::ApiError;
+use crate::middleware::UserContext;
+use crate::token::{self, Claims};
+
+pub const HEADER_NAME: &str = "x-csrf-token";
+pub const FIELD_NAME: &str = "csrf_token";
+// form bodies are only buffered to find the token field, nothing we accept is bigger
+const MAX_FORM_BYTES: usize = 64 * 1024;
+
+// extra origins allowed to post, e.g. "https://wagner.dev,https://www.wagner.dev".
+// without it the origin has to match the host the request was sent to
+static ALLOWED_ORIGINS: Lazy<Vec<String>> = Lazy::new(|| {
+    env::var("CSRF_ALLOWED_ORIGINS")
+        .map(|origins| {
+            origins.split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+});
+
+// the token for the identity in the auth_token cookie, empty for visitors without one.
+// added to every request so templates can embed it
+#[derive(Debug, Clone, Default)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn value(&self) -> &str {
+        &self.0
+    }
+}
+
+// a synchronizer token that needs no storage: an hmac of the preference key, so it is
+// valid for exactly one identity and survives token refreshes
+pub fn token_for(claims: &Claims) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(token::secret_key()).expect("hmac accepts any key length");
+    mac.update(b"csrf:");
+    mac.update(token::get_preference_key(claims).as_bytes());
+
+    mac.finalize().into_bytes().iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// browsers tell us where a request came from, anything but our own pages is refused
+fn same_origin(headers: &HeaderMap) -> bool {
+    if let Some(site) = headers.get("sec-fetch-site").and_then(|value| value.to_str().ok()) {
+        return matches!(site, "same-origin" | "none");
+    }
+
+    match headers.get(header::ORIGIN).and_then(|value| value.to_str().ok()) {
+        Some(origin) => {
+            let host = headers.get(header::HOST).and_then(|value| value.to_str().ok());
+            origin_allowed(origin, host, &ALLOWED_ORIGINS)
+        }
+        // browsers send an origin with every request that has a body, so a body without
+        // one is refused. bodyless requests from scripts and curl still pass
+        None => !has_body(headers),
+    }
+}
+
+fn has_body(headers: &HeaderMap) -> bool {
+    let length = headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    length.is_some_and(|length| length > 0) || headers.contains_key(header::TRANSFER_ENCODING)
+}
+
+fn origin_allowed(origin: &str, host: Option<&str>, allowed: &[String]) -> bool {
+    if allowed.iter().any(|allowed| allowed == origin) {
+        return true;
+    }
+
+    let origin_host = origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://"));
+    origin_host.is_some_and(|origin_host| Some(origin_host) == host)
+}
+
+fn form_field<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// the submitted token from the header, or from the form body for plain html forms
+async fn submitted_token(req: Request) -> Result<(Request, Option<String>), ApiError> {
+    if let Some(value) = req.headers().get(HEADER_NAME).and_then(|value| value.to_str().ok()) {
+        let value = value.to_string();
+        return Ok((req, Some(value)));
+    }
+
+    let is_form = req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((req, None));
+    }
+
+    let (parts, body) = req.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_FORM_BYTES)
+        .await
+        .map_err(|_| ApiError::bad_request("invalid_body", "form body is too large"))?;
+    let value = std::str::from_utf8(&bytes).ok()
+        .and_then(|body| form_field(body, FIELD_NAME))
+        .map(str::to_string);
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), value))
+}
+
+// checks every state-changing request, runs inside jwt_cookie_middleware so the identity is known
+pub async fn csrf_protection(cookies: Cookies, mut req: Request, next: Next) -> Response {
+    let expected = req.extensions()
+        .get::<UserContext>()
+        .and_then(UserContext::get_claims)
+        .map(token_for);
+    req.extensions_mut().insert(CsrfToken(expected.clone().unwrap_or_default()));
+
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if !safe {
+        if !same_origin(req.headers()) {
+            return ApiError::forbidden("cross_origin_request", "cross-origin requests are not allowed").into_response();
+        }
+
+        // visitors without an identity have nothing to forge, the origin check is all they need
+        if let Some(expected) = &expected {
+            let (checked, submitted) = match submitted_token(req).await {
+                Ok(submitted) => submitted,
+                Err(e) => return e.into_response(),
+            };
+            if !submitted.is_some_and(|submitted| constant_time_eq(&submitted, expected)) {
+                return ApiError::forbidden("csrf_token_invalid", "missing or invalid csrf token").into_response();
+            }
+            req = checked;
+        }
+    }
+
+    let mut response = next.run(req).await;
+
+    // the handler may have issued a first token, tell the page the token that goes with it
+    let current = cookies.get("auth_token")
+        .and_then(|cookie| token::verify_token(cookie.value()).ok())
+        .map(|claims| token_for(&claims));
+    if let Some(value) = current.and_then(|current| current.parse().ok()) {
+        response.headers_mut().insert(HEADER_NAME, value);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_is_tied_to_identity() {
+        let claims = token::verify_token(&token::generate_anonymous_token().unwrap()).unwrap();
+        let other = token::verify_token(&token::generate_anonymous_token().unwrap()).unwrap();
+        let refreshed = token::verify_token(&token::reissue_token(&claims, Some("light")).unwrap()).unwrap();
+
+        assert_eq!(token_for(&claims).len(), 64);
+        assert_eq!(token_for(&claims), token_for(&refreshed));
+        assert_ne!(token_for(&claims), token_for(&other));
+    }
+
+    #[test]
+    fn test_origin_checks() {
+        let allowed = vec!["https://wagner.dev".to_string()];
+
+        assert!(origin_allowed("https://wagner.dev", Some("127.0.0.1:8000"), &allowed));
+        assert!(origin_allowed("http://127.0.0.1:8000", Some("127.0.0.1:8000"), &[]));
+        assert!(!origin_allowed("https://evil.example", Some("wagner.dev"), &allowed));
+        assert!(!origin_allowed("null", Some("wagner.dev"), &allowed));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("sec-fetch-site", "cross-site".parse().unwrap());
+        headers.insert(header::ORIGIN, "https://wagner.dev".parse().unwrap());
+        assert!(!same_origin(&headers));
+
+        let mut headers = HeaderMap::new();
+        assert!(same_origin(&headers));
+        headers.insert(header::CONTENT_LENGTH, "12".parse().unwrap());
+        assert!(!same_origin(&headers));
+    }
+
+    #[test]
+    fn test_form_field() {
+        assert_eq!(form_field("theme=dark&csrf_token=abc123", FIELD_NAME), Some("abc123"));
+        assert_eq!(form_field("theme=dark", FIELD_NAME), None);
+    }
+}
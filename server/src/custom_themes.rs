@@ -0,0 +1,282 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio_postgres::Row;
+
+use crate::color::{self, Oklch};
+use crate::database::DbPool;
+use crate::theme::{self, Theme};
+
+pub type SharedCustomThemes = Arc<dyn CustomThemeRepository>;
+
+// wcag aa for normal text
+pub const MIN_CONTRAST: f64 = 4.5;
+const MAX_NAME_LENGTH: usize = 64;
+
+// background/foreground pairs that text is rendered with
+const CONTRAST_PAIRS: [(&str, &str); 7] = [
+    ("background", "foreground"),
+    ("card", "card-foreground"),
+    ("popover", "popover-foreground"),
+    ("primary", "primary-foreground"),
+    ("secondary", "secondary-foreground"),
+    ("muted", "muted-foreground"),
+    ("accent", "accent-foreground"),
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomTheme {
+    pub id: i32,
+    pub name: String,
+    pub base_theme: String,
+    pub variables: BTreeMap<String, String>,
+}
+
+impl CustomTheme {
+    fn from_row(row: &Row) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let variables: serde_json::Value = row.get("variables");
+        Ok(CustomTheme {
+            id: row.get("id"),
+            name: row.get("name"),
+            base_theme: row.get("base_theme"),
+            variables: serde_json::from_value(variables)?,
+        })
+    }
+
+    // overrides scoped to the base theme class, so toggling to another theme still works.
+    // values are validated again so nothing but oklch() colours can reach the page
+    pub fn to_css(&self) -> String {
+        let mut css = format!("html.{} {{", self.base_theme);
+        for (name, value) in &self.variables {
+            if Oklch::parse(value).is_some() {
+                let _ = write!(css, " --{}: {};", name, value);
+            }
+        }
+        css.push_str(" }");
+        css
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PaletteRequest {
+    pub name: String,
+    pub base_theme: String,
+    pub variables: BTreeMap<String, String>,
+}
+
+impl PaletteRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!("name must be between 1 and {} characters", MAX_NAME_LENGTH));
+        }
+
+        let base = theme::registry()
+            .get(&self.base_theme)
+            .ok_or_else(|| format!("unknown base theme '{}'", self.base_theme))?;
+
+        validate_variables(base, &self.variables)
+    }
+}
+
+pub fn validate_variables(base: &Theme, variables: &BTreeMap<String, String>) -> Result<(), String> {
+    if variables.is_empty() {
+        return Err("at least one variable is required".to_string());
+    }
+
+    for (name, value) in variables {
+        if !base.variables.contains_key(name) {
+            return Err(format!("unknown variable '{}'", name));
+        }
+        if Oklch::parse(value).is_none() {
+            return Err(format!("'{}' is not a valid oklch() colour for '{}'", value, name));
+        }
+    }
+
+    for (background, foreground) in CONTRAST_PAIRS {
+        let (Some(bg), Some(fg)) = (
+            effective_color(base, variables, background),
+            effective_color(base, variables, foreground),
+        ) else {
+            continue;
+        };
+
+        let ratio = color::contrast_ratio(&bg, &fg);
+        if ratio < MIN_CONTRAST {
+            return Err(format!(
+                "contrast between '{}' and '{}' is {:.2}:1, at least {}:1 is required",
+                background, foreground, ratio, MIN_CONTRAST
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// the palette value or the base theme's, following var(--x) references a few levels deep
+fn effective_color(base: &Theme, variables: &BTreeMap<String, String>, name: &str) -> Option<Oklch> {
+    let mut name = name.to_string();
+    for _ in 0..4 {
+        let value = variables.get(&name).or_else(|| base.variables.get(&name))?;
+        match value.trim().strip_prefix("var(--").and_then(|rest| rest.strip_suffix(')')) {
+            Some(reference) => name = reference.to_string(),
+            None => return Oklch::parse(value),
+        }
+    }
+    None
+}
+
+#[async_trait]
+pub trait CustomThemeRepository: Send + Sync {
+    async fn list(&self, preference_key: &str) -> Result<Vec<CustomTheme>, Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn create(&self, preference_key: &str, palette: &PaletteRequest) -> Result<CustomTheme, Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn update(&self, preference_key: &str, id: i32, palette: &PaletteRequest) -> Result<Option<CustomTheme>, Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn delete(&self, preference_key: &str, id: i32) -> Result<bool, Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn get(&self, preference_key: &str, id: i32) -> Result<Option<CustomTheme>, Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn set_active(&self, preference_key: &str, id: Option<i32>) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn active(&self, preference_key: &str) -> Result<Option<CustomTheme>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+pub struct PgCustomThemeRepository {
+    db_pool: DbPool,
+}
+
+impl PgCustomThemeRepository {
+    pub fn new(db_pool: DbPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+#[async_trait]
+impl CustomThemeRepository for PgCustomThemeRepository {
+    async fn list(&self, preference_key: &str) -> Result<Vec<CustomTheme>, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(
+            "SELECT id, name, base_theme, variables FROM custom_themes WHERE preference_key = $1 ORDER BY name",
+        ).await?;
+
+        conn.query(&statement, &[&preference_key]).await?
+            .iter()
+            .map(CustomTheme::from_row)
+            .collect()
+    }
+
+    async fn create(&self, preference_key: &str, palette: &PaletteRequest) -> Result<CustomTheme, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        // palettes hang off the preferences row, a visitor who never saved a theme has none yet
+        let statement = conn.prepare_cached(
+            "WITH owner AS (
+                 INSERT INTO user_preferences (preference_key) VALUES ($1)
+                 ON CONFLICT (preference_key) DO NOTHING
+             )
+             INSERT INTO custom_themes (preference_key, name, base_theme, variables)
+             VALUES ($1, $2, $3, $4)
+             RETURNING id, name, base_theme, variables",
+        ).await?;
+
+        let variables = serde_json::to_value(&palette.variables)?;
+        let row = conn.query_one(&statement, &[&preference_key, &palette.name.trim(), &palette.base_theme, &variables]).await?;
+        CustomTheme::from_row(&row)
+    }
+
+    async fn update(&self, preference_key: &str, id: i32, palette: &PaletteRequest) -> Result<Option<CustomTheme>, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(
+            "UPDATE custom_themes SET name = $3, base_theme = $4, variables = $5
+             WHERE preference_key = $1 AND id = $2
+             RETURNING id, name, base_theme, variables",
+        ).await?;
+
+        let variables = serde_json::to_value(&palette.variables)?;
+        let rows = conn.query(&statement, &[&preference_key, &id, &palette.name.trim(), &palette.base_theme, &variables]).await?;
+        rows.first().map(CustomTheme::from_row).transpose()
+    }
+
+    async fn delete(&self, preference_key: &str, id: i32) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached("DELETE FROM custom_themes WHERE preference_key = $1 AND id = $2").await?;
+
+        Ok(conn.execute(&statement, &[&preference_key, &id]).await? > 0)
+    }
+
+    async fn get(&self, preference_key: &str, id: i32) -> Result<Option<CustomTheme>, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(
+            "SELECT id, name, base_theme, variables FROM custom_themes WHERE preference_key = $1 AND id = $2",
+        ).await?;
+
+        let rows = conn.query(&statement, &[&preference_key, &id]).await?;
+        rows.first().map(CustomTheme::from_row).transpose()
+    }
+
+    async fn set_active(&self, preference_key: &str, id: Option<i32>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(
+            "INSERT INTO user_preferences (preference_key, custom_theme_id)
+             VALUES ($1, $2)
+             ON CONFLICT (preference_key)
+             DO UPDATE SET custom_theme_id = $2, updated_at = NOW()",
+        ).await?;
+
+        conn.execute(&statement, &[&preference_key, &id]).await?;
+        Ok(())
+    }
+
+    async fn active(&self, preference_key: &str) -> Result<Option<CustomTheme>, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(
+            "SELECT c.id, c.name, c.base_theme, c.variables
+             FROM user_preferences p
+             JOIN custom_themes c ON c.id = p.custom_theme_id
+             WHERE p.preference_key = $1",
+        ).await?;
+
+        let rows = conn.query(&statement, &[&preference_key]).await?;
+        rows.first().map(CustomTheme::from_row).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette(base_theme: &str, variables: &[(&str, &str)]) -> PaletteRequest {
+        PaletteRequest {
+            name: "mine".to_string(),
+            base_theme: base_theme.to_string(),
+            variables: variables.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+
+    #[test]
+    fn test_builtin_themes_meet_contrast() {
+        for theme in theme::registry().themes() {
+            let overrides: BTreeMap<String, String> = theme.variables.iter()
+                .filter(|(_, value)| Oklch::parse(value).is_some())
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+
+            validate_variables(theme, &overrides).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_palette_validation() {
+        assert!(palette("dark", &[("primary", "oklch(0.75 0.15 150)")]).validate().is_ok());
+
+        let low_contrast = palette("dark", &[("background", "oklch(0.9 0.01 260)")]).validate();
+        assert!(low_contrast.unwrap_err().contains("contrast"));
+
+        assert!(palette("dark", &[("primary", "red")]).validate().is_err());
+        assert!(palette("dark", &[("radius", "oklch(0.5 0.1 1)")]).validate().is_err());
+        assert!(palette("neon", &[("primary", "oklch(0.75 0.15 150)")]).validate().is_err());
+    }
+}
@@ -1,31 +1,126 @@
 use std::env;
-use tokio_postgres::NoTls;
-use bb8::Pool;
+use once_cell::sync::Lazy;
+use futures_util::{stream, StreamExt};
+use tokio_postgres::{AsyncMessage, Client, NoTls, Statement};
+use bb8::{ManageConnection, Pool};
 use bb8_postgres::PostgresConnectionManager;
-use std::collections::HashSet;
-use std::time::Instant;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-pub type DbPool = Pool<PostgresConnectionManager<NoTls>>;
+use crate::theme;
+
+pub type DbPool = Pool<CachingConnectionManager>;
+
+// backend pids of this process's pooled connections. their writes already went through the
+// preference cache, so the notifications they cause are skipped instead of evicting fresh entries
+static POOL_BACKENDS: Lazy<Mutex<HashSet<i32>>> = Lazy::new(Default::default);
+
+// pooled client that remembers the statements it has prepared, since a statement is only valid on the connection that prepared it
+pub struct CachedClient {
+    client: Client,
+    statements: Mutex<HashMap<&'static str, Statement>>,
+    backend_pid: i32,
+}
+
+impl Drop for CachedClient {
+    fn drop(&mut self) {
+        POOL_BACKENDS.lock().unwrap().remove(&self.backend_pid);
+    }
+}
+
+impl CachedClient {
+    pub async fn prepare_cached(&self, query: &'static str) -> Result<Statement, tokio_postgres::Error> {
+        if let Some(statement) = self.statements.lock().unwrap().get(query) {
+            return Ok(statement.clone());
+        }
+
+        let statement = self.client.prepare(query).await?;
+        self.statements.lock().unwrap().insert(query, statement.clone());
+        Ok(statement)
+    }
+}
+
+impl Deref for CachedClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl DerefMut for CachedClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+}
+
+pub struct CachingConnectionManager {
+    inner: PostgresConnectionManager<NoTls>,
+}
+
+impl ManageConnection for CachingConnectionManager {
+    type Connection = CachedClient;
+    type Error = tokio_postgres::Error;
+
+    async fn connect(&self) -> Result<CachedClient, tokio_postgres::Error> {
+        let client = self.inner.connect().await?;
+        let backend_pid: i32 = client.query_one("SELECT pg_backend_pid()", &[]).await?.get(0);
+        POOL_BACKENDS.lock().unwrap().insert(backend_pid);
+
+        Ok(CachedClient {
+            client,
+            statements: Mutex::new(HashMap::new()),
+            backend_pid,
+        })
+    }
+
+    async fn is_valid(&self, conn: &mut CachedClient) -> Result<(), tokio_postgres::Error> {
+        self.inner.is_valid(&mut conn.client).await
+    }
+
+    fn has_broken(&self, conn: &mut CachedClient) -> bool {
+        self.inner.has_broken(&mut conn.client)
+    }
+}
 
 const DEFAULT_DB_HOST: &str = "127.0.0.1";
 const DEFAULT_DB_USER: &str = "postgres";
 const DEFAULT_DB_PASSWORD: &str = "postgres";
 const DEFAULT_DB_NAME: &str = "dev";
+const DEFAULT_MIGRATION_LOCK_TIMEOUT_SECS: u64 = 60;
 
-pub async fn init_db() -> Result<DbPool, Box<dyn std::error::Error + Send + Sync>> {
-    let db_host = env::var("DB_HOST").unwrap_or_else(|_| DEFAULT_DB_HOST.to_string());
+// arbitrary but stable key shared by every instance ("wagner" in hex)
+const MIGRATION_LOCK_KEY: i64 = 0x7761676e6572;
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const LOCK_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const LISTEN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// notified with the preference_key by the trigger added in V2
+pub const PREFERENCES_CHANNEL: &str = "user_preferences_changed";
+
+fn db_host() -> String {
+    env::var("DB_HOST").unwrap_or_else(|_| DEFAULT_DB_HOST.to_string())
+}
+
+fn connection_string() -> String {
     let db_user = env::var("DB_USER").unwrap_or_else(|_| DEFAULT_DB_USER.to_string());
     let db_password = env::var("DB_PASSWORD").unwrap_or_else(|_| DEFAULT_DB_PASSWORD.to_string());
     let db_name = env::var("DB_NAME").unwrap_or_else(|_| DEFAULT_DB_NAME.to_string());
-    
-    let connection_string = format!(
+
+    format!(
         "host={} user={} password={} dbname={}",
-        db_host, db_user, db_password, db_name
-    );
-    
-    println!("connecting to pgsql database at {}...", db_host);
+        db_host(), db_user, db_password, db_name
+    )
+}
+
+pub async fn init_db() -> Result<DbPool, Box<dyn std::error::Error + Send + Sync>> {
+    println!("connecting to pgsql database at {}...", db_host());
     
-    let manager = PostgresConnectionManager::new_from_stringlike(connection_string, NoTls)?;
+    let manager = CachingConnectionManager {
+        inner: PostgresConnectionManager::new_from_stringlike(connection_string(), NoTls)?,
+    };
     
     let pool = Pool::builder()
         .max_size(4)
@@ -58,14 +153,246 @@ pub async fn init_db() -> Result<DbPool, Box<dyn std::error::Error + Send + Sync
     Ok(pool)
 }
 
+// keep a dedicated connection subscribed to a channel, reconnecting whenever it drops.
+// on_subscribed runs after every successful LISTEN since notifications may have been missed in between
+pub fn spawn_listener<N, S>(channel: &'static str, on_notify: N, on_subscribed: S)
+where
+    N: Fn(&str) + Send + Sync + 'static,
+    S: Fn() + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listen(channel, &on_notify, &on_subscribed).await {
+                eprintln!("listener on {} failed: {}", channel, e);
+            }
+            tokio::time::sleep(LISTEN_RETRY_INTERVAL).await;
+        }
+    });
+}
+
+async fn listen<N, S>(
+    channel: &str,
+    on_notify: &N,
+    on_subscribed: &S,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    N: Fn(&str),
+    S: Fn(),
+{
+    let (client, mut connection) = tokio_postgres::connect(&connection_string(), NoTls).await?;
+    let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+
+    // the connection has to be driven while the LISTEN itself is in flight
+    let query = format!("LISTEN {}", channel);
+    let subscribe = client.batch_execute(&query);
+    tokio::pin!(subscribe);
+    loop {
+        tokio::select! {
+            result = &mut subscribe => {
+                result?;
+                break;
+            }
+            message = messages.next() => {
+                if message.transpose()?.is_none() {
+                    return Err("connection closed before LISTEN completed".into());
+                }
+            }
+        }
+    }
+
+    println!("listening for notifications on {}", channel);
+    on_subscribed();
+
+    while let Some(message) = messages.next().await {
+        if let AsyncMessage::Notification(notification) = message?
+            && !POOL_BACKENDS.lock().unwrap().contains(&notification.process_id())
+        {
+            on_notify(notification.payload());
+        }
+    }
+
+    Err("connection closed".into())
+}
+
 mod embedded {
     use refinery::embed_migrations;
     embed_migrations!("migrations");
 }
 
-pub async fn run_migrations(db_pool: &DbPool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut conn = db_pool.get().await?;
+// identifies this process in lock logs, INSTANCE_ID can be set per deployment
+pub fn instance_id() -> String {
+    env::var("INSTANCE_ID").unwrap_or_else(|_| format!("pid-{}", std::process::id()))
+}
+
+// a single attempt, for work that should be skipped rather than queued when another instance does it
+pub async fn try_advisory_lock(
+    conn: &Client,
+    key: i64,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let row = conn.query_one("SELECT pg_try_advisory_lock($1)", &[&key]).await?;
+    Ok(row.get(0))
+}
+
+// who holds an advisory lock, by application_name, which run_migrations sets to the instance id
+async fn advisory_lock_holder(
+    conn: &Client,
+    key: i64,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    // a bigint key is stored split over classid and objid, objsubid 1 marks the single key form
+    let row = conn.query_opt(
+        "SELECT activity.application_name, activity.pid FROM pg_locks locks
+         JOIN pg_stat_activity activity ON activity.pid = locks.pid
+         WHERE locks.locktype = 'advisory' AND locks.granted AND locks.objsubid = 1
+           AND locks.classid = ($1::bigint >> 32)::oid AND locks.objid = ($1::bigint & 4294967295)::oid",
+        &[&key],
+    ).await?;
+
+    Ok(row.map(|row| {
+        let name: String = row.get(0);
+        let pid: i32 = row.get(1);
+        if name.is_empty() {
+            format!("backend pid {}", pid)
+        } else {
+            format!("{} (backend pid {})", name, pid)
+        }
+    }))
+}
+
+// poll for a session level advisory lock until it is acquired or the timeout elapses, reporting
+// the holder now and then. returns whether the lock had to be waited for
+pub async fn acquire_advisory_lock(
+    conn: &Client,
+    key: i64,
+    timeout: Duration,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let start = Instant::now();
+    let mut last_report: Option<Instant> = None;
+
+    loop {
+        if try_advisory_lock(conn, key).await? {
+            return Ok(last_report.is_some());
+        }
+
+        if start.elapsed() >= timeout {
+            return Err(format!("timed out after {:?} waiting for advisory lock {}", timeout, key).into());
+        }
+
+        if last_report.is_none_or(|reported| reported.elapsed() >= LOCK_REPORT_INTERVAL) {
+            // the holder can let go between the two queries, then there is nobody to report
+            match advisory_lock_holder(conn, key).await {
+                Ok(Some(holder)) => println!("advisory lock {} is held by {}, waiting...", key, holder),
+                Ok(None) => {}
+                Err(e) => eprintln!("looking up the holder of advisory lock {} failed: {}", key, e),
+            }
+            last_report = Some(Instant::now());
+        }
+        tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+    }
+}
+
+pub async fn release_advisory_lock(
+    conn: &Client,
+    key: i64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    conn.execute("SELECT pg_advisory_unlock($1)", &[&key]).await?;
+    Ok(())
+}
+
+// a connection outside the pool for holding session level advisory locks. if unlocking fails it
+// is dropped, closing the session releases the lock, where a pooled one would go back still holding it
+pub async fn connect_dedicated(application_name: &str) -> Result<Client, Box<dyn std::error::Error + Send + Sync>> {
+    let name = application_name.replace('\\', "\\\\").replace('\'', "\\'");
+    let (client, connection) = tokio_postgres::connect(
+        &format!("{} application_name='{}'", connection_string(), name),
+        NoTls,
+    ).await?;
+
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("dedicated connection failed: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+pub async fn run_migrations() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let lock_timeout = env::var("MIGRATION_LOCK_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_MIGRATION_LOCK_TIMEOUT_SECS);
+    let instance = instance_id();
+    // named after the instance, so the others waiting for the lock can tell who holds it
+    let mut conn = connect_dedicated(&instance).await?;
+
+    println!("waiting for migration lock as {}...", instance);
+    let waited = acquire_advisory_lock(&conn, MIGRATION_LOCK_KEY, Duration::from_secs(lock_timeout)).await?;
+    println!("migration lock held by {}", instance);
+
+    let result = async {
+        // another instance migrated while we were waiting, so only verify the schema unless it is still behind
+        if waited && schema_is_current(&conn).await? {
+            println!("schema already at latest version, skipping migrations");
+            return Ok(());
+        }
+        apply_migrations(&mut conn).await
+    }.await;
+
+    // release even if the migrations failed so other instances are not stuck. the migration
+    // error is the one worth returning, a failed unlock is covered by closing the connection
+    match release_advisory_lock(&conn, MIGRATION_LOCK_KEY).await {
+        Ok(()) => println!("migration lock released by {}", instance),
+        Err(e) => eprintln!("releasing migration lock failed, closing the connection instead: {}", e),
+    }
+    drop(conn);
+
+    result
+}
+
+// make every theme from themes.toml, and following the os, a valid user_preferences.theme value.
+// themes removed from the file stay in the table since stored preferences may still reference them
+pub async fn sync_themes(db_pool: &DbPool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let conn = db_pool.get().await?;
+    let themes = theme::registry().themes();
+
+    let rows = themes.iter()
+        .map(|definition| (definition.name.as_str(), definition.label.as_str()))
+        .chain(std::iter::once((theme::SYSTEM_THEME, theme::SYSTEM_LABEL)));
+    for (name, label) in rows {
+        conn.execute(
+            "INSERT INTO themes (name, label) VALUES ($1, $2)
+             ON CONFLICT (name) DO UPDATE SET label = $2",
+            &[&name, &label],
+        ).await?;
+    }
+
+    // V4 adds the foreign key NOT VALID, existing preferences can only be checked now. a no-op once validated
+    conn.batch_execute("ALTER TABLE user_preferences VALIDATE CONSTRAINT user_preferences_theme_fkey").await?;
+
+    println!("{} themes registered", themes.len());
+    Ok(())
+}
+
+async fn schema_is_current(conn: &Client) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let latest_embedded = embedded::migrations::runner()
+        .get_migrations()
+        .iter()
+        .map(|migration| migration.version())
+        .max();
+
+    let applied: Option<i32> = conn
+        .query_one("SELECT MAX(version) FROM refinery_schema_history", &[])
+        .await
+        .map(|row| row.get(0))
+        .unwrap_or(None);
+
+    Ok(match (latest_embedded, applied) {
+        (Some(latest), Some(applied)) => i64::from(applied) >= i64::from(latest),
+        (None, _) => true,
+        (Some(_), None) => false,
+    })
+}
 
+async fn apply_migrations(conn: &mut Client) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     println!("determining migrations...");
     let start = Instant::now();
 
@@ -80,7 +407,7 @@ pub async fn run_migrations(db_pool: &DbPool) -> Result<(), Box<dyn std::error::
         .map(|row| row.get::<_, i32>("version"))
         .collect();
 
-    embedded::migrations::runner().run_async(&mut *conn).await?;
+    embedded::migrations::runner().run_async(conn).await?;
 
     // fetch history again to determine which migrations were applied in this run
     let post_rows = conn
@@ -0,0 +1,186 @@
+use axum::{
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::time::Duration;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// what every /api route answers with when it fails. `code` is stable and meant for
+// programs, `message` is for people and may change. internal causes are only logged
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    source: Option<BoxError>,
+    retry_after: Option<Duration>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    success: bool,
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorDetail<'a> {
+    code: &'a str,
+    message: &'a str,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self { status, code, message: message.into(), source: None, retry_after: None }
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, code, message)
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code, message)
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, code, message)
+    }
+
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", "too many requests, try again later")
+        }
+    }
+
+    pub fn internal(e: impl Into<BoxError>) -> Self {
+        Self {
+            source: Some(e.into()),
+            ..Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "internal error")
+        }
+    }
+
+    pub fn unavailable(e: impl Into<BoxError>) -> Self {
+        Self {
+            source: Some(e.into()),
+            ..Self::new(StatusCode::SERVICE_UNAVAILABLE, "storage_unavailable", "preferences are temporarily unavailable")
+        }
+    }
+
+    // a failed repository call: 503 when postgres could not be reached, 500 for anything else
+    pub fn storage(e: BoxError) -> Self {
+        if is_connection_error(e.as_ref()) {
+            Self::unavailable(e)
+        } else {
+            Self::internal(e)
+        }
+    }
+}
+
+fn is_connection_error(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    if e.downcast_ref::<bb8::RunError<tokio_postgres::Error>>().is_some() {
+        return true;
+    }
+
+    // a lost connection or an i/o failure underneath it. other errors without an sqlstate,
+    // like a row that fails to convert, are bugs and stay a 500
+    let Some(e) = e.downcast_ref::<tokio_postgres::Error>() else {
+        return false;
+    };
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        if cause.is::<std::io::Error>() {
+            return true;
+        }
+        source = cause.source();
+    }
+    e.is_closed()
+}
+
+// axum's Json, but a body that fails to parse gets the api's error shape instead of plain text
+pub struct ApiJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(|rejection| ApiError::bad_request("invalid_json", rejection.body_text()))?;
+        Ok(ApiJson(value))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let Some(source) = &self.source {
+            eprintln!("request failed with {}: {}", self.code, source);
+        }
+
+        let body = ErrorBody {
+            success: false,
+            error: ErrorDetail {
+                code: self.code,
+                message: &self.message,
+            },
+        };
+
+        let mut response = (self.status, Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            // whole seconds, rounded up so clients never retry too early
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.headers_mut().insert(header::RETRY_AFTER, seconds.into());
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_internal_errors_are_not_leaked() {
+        let response = ApiError::storage("password authentication failed for user \"postgres\"".into()).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "internal_error");
+        assert!(!body.to_string().contains("password"));
+    }
+
+    #[tokio::test]
+    async fn test_json_rejection_uses_api_errors() {
+        let req = Request::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from("{\"theme\":"))
+            .unwrap();
+        let Err(error) = ApiJson::<serde_json::Value>::from_request(req, &()).await else {
+            panic!("truncated json was accepted");
+        };
+
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "invalid_json");
+    }
+}
@@ -0,0 +1,100 @@
+use axum::Router;
+
+// checked by base.html, release builds render no reload script and serve no endpoint
+pub const ENABLED: bool = cfg!(debug_assertions);
+
+pub const PATH: &str = "/__live-reload";
+
+#[cfg(debug_assertions)]
+mod dev {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+    use futures_util::{stream, Stream};
+    use std::collections::hash_map::DefaultHasher;
+    use std::convert::Infallible;
+    use std::hash::{Hash, Hasher};
+    use std::path::Path;
+    use std::time::Duration;
+    use std::{fs, thread};
+    use tokio::sync::watch;
+    use uuid::Uuid;
+
+    const STATIC_DIR: &str = "static";
+    const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+    // names, sizes and mtimes of everything below static/, any tailwind build output changes it
+    fn snapshot(directory: &Path, hasher: &mut DefaultHasher) {
+        let Ok(entries) = fs::read_dir(directory) else {
+            return;
+        };
+
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+        entries.sort_by_key(|entry| entry.path());
+        for entry in entries {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+
+            path.hash(hasher);
+            if metadata.is_dir() {
+                snapshot(&path, hasher);
+            } else {
+                metadata.len().hash(hasher);
+                metadata.modified().ok().hash(hasher);
+            }
+        }
+    }
+
+    fn static_version() -> u64 {
+        let mut hasher = DefaultHasher::new();
+        snapshot(Path::new(STATIC_DIR), &mut hasher);
+        hasher.finish()
+    }
+
+    // "{process}-{static}", a restarted server or a rebuilt static/ both change it
+    pub fn spawn_watcher() -> watch::Receiver<String> {
+        let process = Uuid::new_v4().simple().to_string();
+        let mut current = static_version();
+        let (sender, receiver) = watch::channel(format!("{}-{:x}", process, current));
+
+        // polling keeps this free of a file watcher dependency, static/ is a handful of files
+        thread::spawn(move || loop {
+            thread::sleep(POLL_INTERVAL);
+            let version = static_version();
+            if version != current {
+                current = version;
+                println!("live reload: static files changed");
+                if sender.send(format!("{}-{:x}", process, current)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        receiver
+    }
+
+    // the current build on connect, then every change, the page reloads when it sees a different one
+    pub async fn events(mut version: watch::Receiver<String>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+        version.mark_changed();
+        let events = stream::unfold(version, |mut version| async move {
+            version.changed().await.ok()?;
+            let build = version.borrow_and_update().clone();
+            Some((Ok(Event::default().event("build").data(build)), version))
+        });
+
+        Sse::new(events).keep_alive(KeepAlive::default())
+    }
+}
+
+// merged into the app, empty in release builds
+pub fn router() -> Router {
+    #[cfg(debug_assertions)]
+    {
+        let version = dev::spawn_watcher();
+        println!("live reload: watching static/");
+        Router::new().route(PATH, axum::routing::get(move || dev::events(version.clone())))
+    }
+
+    #[cfg(not(debug_assertions))]
+    Router::new()
+}
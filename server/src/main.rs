@@ -1,17 +1,29 @@
 use axum::{
     extract::Extension,
     middleware as axum_mw,
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router
 };
-use tower_http::services::ServeDir;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tower_cookies::CookieManagerLayer;
 
-pub const DEFAULT_THEME: &str = "dark";
-
+mod assets;
+mod cache;
+mod cleanup;
+mod color;
+mod csrf;
+mod custom_themes;
 mod database;
+mod error;
+mod live_reload;
+mod preference_schema;
+mod preferences;
+mod rate_limit;
+mod security;
 mod routes;
 mod middleware;
+mod theme;
 mod token;
 use middleware as mw;
 
@@ -20,21 +32,79 @@ async fn main() {
     dotenv::dotenv().ok();
     
     let db_pool = database::init_db().await.expect("database connection failed");
-    database::run_migrations(&db_pool).await.expect("database migrations failed");
+    database::run_migrations().await.expect("database migrations failed");
+    database::sync_themes(&db_pool).await.expect("theme registration failed");
 
-    let app = Router::new()
+    let cleanup_config = cleanup::CleanupConfig::from_env();
+    // `cargo run -p dev -- cleanup` sweeps once and exits instead of serving
+    if std::env::args().nth(1).as_deref() == Some("cleanup") {
+        match cleanup::run_once(&db_pool, cleanup_config).await.expect("preference cleanup failed") {
+            Some(_) => {}
+            None => println!("preference cleanup is running on another instance"),
+        }
+        return;
+    }
+    cleanup::spawn(db_pool.clone(), cleanup_config);
+
+    let rate_limiter: rate_limit::SharedRateLimiter = Arc::new(rate_limit::RateLimiter::from_env(db_pool.clone()));
+    rate_limit::spawn_pruner(rate_limiter.clone());
+    let custom_theme_cache = Arc::new(cache::CachedCustomThemeRepository::from_env(
+        Arc::new(custom_themes::PgCustomThemeRepository::new(db_pool.clone())),
+    ));
+    let preference_cache = Arc::new(cache::CachedPreferencesRepository::from_env(
+        Arc::new(preferences::PgPreferencesRepository::new(db_pool)),
+    ));
+    let (invalidated, invalidated_themes) = (preference_cache.clone(), custom_theme_cache.clone());
+    let (cleared, cleared_themes) = (preference_cache.clone(), custom_theme_cache.clone());
+    database::spawn_listener(
+        database::PREFERENCES_CHANNEL,
+        move |preference_key| {
+            invalidated.invalidate(preference_key);
+            invalidated_themes.invalidate(preference_key);
+        },
+        move || {
+            cleared.clear();
+            cleared_themes.clear();
+        },
+    );
+    let custom_themes: custom_themes::SharedCustomThemes = custom_theme_cache;
+    cache::spawn_stats_logger(preference_cache.clone());
+    let preferences: preferences::SharedPreferences = preference_cache;
+    let theme_storage = preferences::ThemeStorage::from_env();
+
+    // only pages pick their theme from the color scheme hint, static files and the api don't vary on it
+    let pages = Router::new()
         .route("/", get(routes::pages::index))
+        .fallback(routes::pages::not_found)
+        .layer(axum_mw::from_fn(mw::client_hints));
+
+    let app = Router::new()
         .route("/api/theme", get(routes::themes::get_theme))
         .route("/api/theme", post(routes::themes::set_theme))
+        .route("/api/preferences", get(routes::preferences::get_preferences).patch(routes::preferences::patch_preferences))
         .route("/api/icon/{name}", get(routes::icons::get_icon))
-        .nest_service("/static", ServeDir::new("static"))
-        .fallback(routes::pages::not_found)
-        .layer(Extension(db_pool))
+        .route("/api/custom-themes", get(routes::custom_themes::list).post(routes::custom_themes::create))
+        .route("/api/custom-themes/active", delete(routes::custom_themes::deactivate))
+        .route("/api/custom-themes/{id}", put(routes::custom_themes::update).delete(routes::custom_themes::delete))
+        .route("/api/custom-themes/{id}/activate", post(routes::custom_themes::activate))
+        .merge(pages)
+        .merge(live_reload::router())
+        .nest_service("/static", assets::router())
+        .layer(Extension(preferences))
+        .layer(Extension(custom_themes))
+        .layer(Extension(theme_storage))
+        .layer(axum_mw::from_fn(csrf::csrf_protection))
+        // added below the csrf layer so it skips it, violation reports are posted by the
+        // browser itself without origin or token and change nothing
+        .route(security::CSP_REPORT_PATH, post(routes::csp::report))
+        .layer(axum_mw::from_fn_with_state(rate_limiter, rate_limit::rate_limit))
         .layer(axum_mw::from_fn(mw::jwt_cookie_middleware))
+        .layer(axum_mw::from_fn(security::security_headers))
         .layer(CookieManagerLayer::new())
         .layer(axum_mw::from_fn(mw::logger));
 
     let listener = tokio::net::TcpListener::bind("127.0.0.1:8000").await.unwrap();
     println!("server started");
-    axum::serve(listener, app).await.unwrap();
+    // the peer address is the rate limiting key when there is no trusted proxy in front
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
 use axum::{
     extract::Request,
+    http::{header, HeaderValue},
     middleware::Next,
     response::Response,
 };
@@ -26,6 +27,20 @@ pub async fn logger(req: Request, next: Next) -> Response {
     response
 }
 
+// ask browsers for their color scheme so first-time visitors get the right theme without a flash.
+// Critical-CH makes supporting browsers retry the very first request with the hint attached
+pub async fn client_hints(req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+
+    let hint = HeaderValue::from_static("Sec-CH-Prefers-Color-Scheme");
+    let headers = response.headers_mut();
+    headers.insert("accept-ch", hint.clone());
+    headers.insert("critical-ch", hint.clone());
+    headers.append(header::VARY, hint);
+
+    response
+}
+
 pub async fn jwt_cookie_middleware(
     cookies: Cookies,
     mut req: Request,
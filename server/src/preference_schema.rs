@@ -0,0 +1,156 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::theme::{self, SYSTEM_THEME};
+
+pub type PreferenceMap = Map<String, Value>;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PreferenceKind {
+    // any name from themes.toml or "system"
+    Theme,
+    Integer { min: i64, max: i64 },
+    Boolean,
+    Choice { options: &'static [&'static str] },
+    // e.g. "en" or "de-AT"
+    LanguageTag,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PreferenceDefinition {
+    pub key: &'static str,
+    #[serde(flatten)]
+    pub kind: PreferenceKind,
+    #[serde(skip)]
+    default: fn() -> Value,
+}
+
+// every preference a visitor can store, adding one here is all the api needs
+pub const PREFERENCES: [PreferenceDefinition; 5] = [
+    PreferenceDefinition {
+        key: "theme",
+        kind: PreferenceKind::Theme,
+        default: || Value::from(SYSTEM_THEME),
+    },
+    PreferenceDefinition {
+        key: "font_size",
+        kind: PreferenceKind::Integer { min: 12, max: 24 },
+        default: || Value::from(16),
+    },
+    PreferenceDefinition {
+        key: "reduced_motion",
+        kind: PreferenceKind::Choice { options: &["system", "reduce", "no-preference"] },
+        default: || Value::from("system"),
+    },
+    PreferenceDefinition {
+        key: "language",
+        kind: PreferenceKind::LanguageTag,
+        default: || Value::from("en"),
+    },
+    PreferenceDefinition {
+        key: "code_ligatures",
+        kind: PreferenceKind::Boolean,
+        default: || Value::from(true),
+    },
+];
+
+impl PreferenceDefinition {
+    pub fn default_value(&self) -> Value {
+        (self.default)()
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<(), String> {
+        let valid = match self.kind {
+            PreferenceKind::Theme => value.as_str().is_some_and(|name| theme::registry().is_valid_choice(name)),
+            PreferenceKind::Integer { min, max } => value.as_i64().is_some_and(|n| (min..=max).contains(&n)),
+            PreferenceKind::Boolean => value.is_boolean(),
+            PreferenceKind::Choice { options } => value.as_str().is_some_and(|s| options.contains(&s)),
+            PreferenceKind::LanguageTag => value.as_str().is_some_and(is_language_tag),
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("invalid value {} for '{}'", value, self.key))
+        }
+    }
+}
+
+pub fn definition(key: &str) -> Option<&'static PreferenceDefinition> {
+    PREFERENCES.iter().find(|definition| definition.key == key)
+}
+
+// a patch sets keys to new values, null resets a key to its default
+pub fn validate_patch(patch: &PreferenceMap) -> Result<(), String> {
+    if patch.is_empty() {
+        return Err("no preferences given".to_string());
+    }
+
+    for (key, value) in patch {
+        let definition = definition(key).ok_or_else(|| format!("unknown preference '{}'", key))?;
+        if !value.is_null() {
+            definition.validate(value)?;
+        }
+    }
+
+    Ok(())
+}
+
+// every known preference, stored values where valid and defaults otherwise
+pub fn effective(stored: &PreferenceMap) -> PreferenceMap {
+    PREFERENCES
+        .iter()
+        .map(|definition| {
+            let value = stored
+                .get(definition.key)
+                .filter(|value| definition.validate(value).is_ok())
+                .cloned()
+                .unwrap_or_else(|| definition.default_value());
+            (definition.key.to_string(), value)
+        })
+        .collect()
+}
+
+fn is_language_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let language = parts.next().unwrap_or_default();
+    let region = parts.next();
+
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_lowercase())
+        && region.is_none_or(|region| region.len() == 2 && region.chars().all(|c| c.is_ascii_uppercase()))
+        && parts.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn map(value: Value) -> PreferenceMap {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_validate_patch() {
+        assert!(validate_patch(&map(json!({ "font_size": 18, "language": "de-AT", "theme": null }))).is_ok());
+
+        assert!(validate_patch(&map(json!({ "font_size": 40 }))).is_err());
+        assert!(validate_patch(&map(json!({ "code_ligatures": "yes" }))).is_err());
+        assert!(validate_patch(&map(json!({ "theme": "neon" }))).is_err());
+        assert!(validate_patch(&map(json!({ "language": "english" }))).is_err());
+        assert!(validate_patch(&map(json!({ "colour": "red" }))).is_err());
+        assert!(validate_patch(&map(json!({}))).is_err());
+    }
+
+    #[test]
+    fn test_effective_fills_defaults_and_drops_invalid() {
+        let effective = effective(&map(json!({ "font_size": 20, "reduced_motion": "sometimes" })));
+
+        assert_eq!(effective.len(), PREFERENCES.len());
+        assert_eq!(effective["font_size"], json!(20));
+        assert_eq!(effective["reduced_motion"], json!("system"));
+        assert_eq!(effective["theme"], json!(SYSTEM_THEME));
+    }
+}
@@ -0,0 +1,387 @@
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use std::borrow::Cow;
+use std::env;
+use std::sync::Arc;
+
+use crate::database::DbPool;
+use crate::middleware::UserContext;
+use crate::preference_schema::PreferenceMap;
+use crate::token;
+use crate::theme::{self, SYSTEM_THEME};
+
+pub type SharedPreferences = Arc<dyn PreferencesRepository>;
+
+pub const CLIENT_HINT_HEADER: &str = "sec-ch-prefers-color-scheme";
+
+const SELECT_THEME: &str = "SELECT theme FROM user_preferences WHERE preference_key = $1";
+const UPSERT_THEME: &str = "INSERT INTO user_preferences (preference_key, theme)
+     VALUES ($1, $2)
+     ON CONFLICT (preference_key)
+     DO UPDATE SET theme = $2, updated_at = NOW()";
+const SELECT_PREFERENCES: &str = "SELECT theme, settings FROM user_preferences WHERE preference_key = $1";
+// theme keeps its own column for the themes foreign key, everything else lives in settings.
+// $2 says whether the patch touches the theme at all, since $3 being null means reset
+const PATCH_PREFERENCES: &str = "INSERT INTO user_preferences (preference_key, theme, settings)
+     VALUES ($1, $3, $4)
+     ON CONFLICT (preference_key)
+     DO UPDATE SET
+         theme = CASE WHEN $2 THEN $3 ELSE user_preferences.theme END,
+         settings = (user_preferences.settings || $4) - $5::text[],
+         updated_at = NOW()
+     RETURNING theme, settings";
+
+// where a chosen theme is persisted, set with THEME_STORAGE=database|token|both
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThemeStorage {
+    Database,
+    // signed jwt claim only, pages render without touching the database
+    Token,
+    Both,
+}
+
+impl ThemeStorage {
+    pub fn from_env() -> Self {
+        match env::var("THEME_STORAGE").as_deref() {
+            Ok("database") => ThemeStorage::Database,
+            Ok("token") => ThemeStorage::Token,
+            _ => ThemeStorage::Both,
+        }
+    }
+
+    pub fn uses_database(self) -> bool {
+        matches!(self, ThemeStorage::Database | ThemeStorage::Both)
+    }
+
+    pub fn uses_token(self) -> bool {
+        matches!(self, ThemeStorage::Token | ThemeStorage::Both)
+    }
+}
+
+// every preference read and write goes through this trait so handlers never embed sql
+#[async_trait]
+pub trait PreferencesRepository: Send + Sync {
+    async fn get_theme(&self, preference_key: &str) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn save_theme(&self, preference_key: &str, theme: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    // stored values only, an empty map for visitors without a row
+    async fn get_preferences(&self, preference_key: &str) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>>;
+
+    // apply a validated patch, null values remove the key, returns the stored values afterwards
+    async fn update_preferences(&self, preference_key: &str, patch: &PreferenceMap) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+fn preferences_from_row(row: &tokio_postgres::Row) -> PreferenceMap {
+    let mut preferences = match row.get::<_, serde_json::Value>("settings") {
+        serde_json::Value::Object(settings) => settings,
+        _ => PreferenceMap::new(),
+    };
+
+    if let Some(theme) = row.get::<_, Option<String>>("theme") {
+        preferences.insert("theme".to_string(), theme.into());
+    }
+
+    preferences
+}
+
+pub struct PgPreferencesRepository {
+    db_pool: DbPool,
+}
+
+impl PgPreferencesRepository {
+    pub fn new(db_pool: DbPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+#[async_trait]
+impl PreferencesRepository for PgPreferencesRepository {
+    async fn get_theme(&self, preference_key: &str) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(SELECT_THEME).await?;
+        let rows = conn.query(&statement, &[&preference_key]).await?;
+
+        Ok(rows.first().and_then(|row| row.get("theme")))
+    }
+
+    async fn save_theme(&self, preference_key: &str, theme: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(UPSERT_THEME).await?;
+        conn.execute(&statement, &[&preference_key, &theme]).await?;
+
+        Ok(())
+    }
+
+    async fn get_preferences(&self, preference_key: &str) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(SELECT_PREFERENCES).await?;
+        let rows = conn.query(&statement, &[&preference_key]).await?;
+
+        match rows.first() {
+            Some(row) => Ok(preferences_from_row(row)),
+            None => Ok(PreferenceMap::new()),
+        }
+    }
+
+    async fn update_preferences(&self, preference_key: &str, patch: &PreferenceMap) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>> {
+        let touches_theme = patch.contains_key("theme");
+        let theme = patch.get("theme").and_then(|theme| theme.as_str());
+
+        let mut settings = PreferenceMap::new();
+        let mut removed = Vec::new();
+        for (key, value) in patch.iter().filter(|(key, _)| *key != "theme") {
+            if value.is_null() {
+                removed.push(key.as_str());
+            } else {
+                settings.insert(key.clone(), value.clone());
+            }
+        }
+        let settings = serde_json::Value::Object(settings);
+
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(PATCH_PREFERENCES).await?;
+        let row = conn.query_one(&statement, &[&preference_key, &touches_theme, &theme, &settings, &removed]).await?;
+
+        Ok(preferences_from_row(&row))
+    }
+}
+
+// theme to render plus whether the page should keep following the os setting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedTheme {
+    pub theme: Cow<'static, str>,
+    pub follows_system: bool,
+}
+
+// database > token claim > client hint > default
+pub async fn resolve_theme(
+    user_context: &UserContext,
+    storage: ThemeStorage,
+    preferences: &dyn PreferencesRepository,
+    headers: &HeaderMap,
+) -> ResolvedTheme {
+    match stored_theme(user_context, storage, preferences).await {
+        Some(theme) if theme != SYSTEM_THEME => ResolvedTheme {
+            theme: Cow::Owned(theme),
+            follows_system: false,
+        },
+        // "system" and visitors without a stored choice both follow the os
+        _ => ResolvedTheme {
+            theme: Cow::Borrowed(system_theme(headers)),
+            follows_system: true,
+        },
+    }
+}
+
+async fn stored_theme(
+    user_context: &UserContext,
+    storage: ThemeStorage,
+    preferences: &dyn PreferencesRepository,
+) -> Option<String> {
+    if storage.uses_database() && let Some(claims) = user_context.get_claims() {
+        let preference_key = token::get_preference_key(claims);
+
+        // the foreign key is NOT VALID, so rows can still name a theme themes.toml has dropped
+        match preferences.get_theme(&preference_key).await {
+            Ok(Some(theme)) if theme::registry().is_valid_choice(&theme) => return Some(theme),
+            Ok(_) => {}
+            Err(e) => eprintln!("theme lookup failed, falling back: {}", e),
+        }
+    }
+
+    if storage.uses_token() {
+        let claimed = user_context.get_claims().and_then(|claims| claims.theme.as_deref());
+        return claimed.filter(|name| theme::registry().is_valid_choice(name)).map(str::to_string);
+    }
+
+    None
+}
+
+// Sec-CH-Prefers-Color-Scheme is a structured header string, e.g. "dark" including the quotes
+// the theme a visitor following the os gets rendered with
+pub fn system_theme(headers: &HeaderMap) -> &'static str {
+    hinted_theme(headers).unwrap_or_else(theme::default_theme)
+}
+
+fn hinted_theme(headers: &HeaderMap) -> Option<&'static str> {
+    let value = headers.get(CLIENT_HINT_HEADER)?.to_str().ok()?;
+    match value.trim().trim_matches('"') {
+        "light" => Some("light"),
+        "dark" => Some("dark"),
+        _ => None,
+    }
+}
+
+pub async fn get_user_preferences(
+    claims: &token::Claims,
+    preferences: &dyn PreferencesRepository,
+) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>> {
+    let preference_key = token::get_preference_key(claims);
+    preferences.get_preferences(&preference_key).await
+}
+
+pub async fn update_user_preferences(
+    claims: &token::Claims,
+    patch: &PreferenceMap,
+    preferences: &dyn PreferencesRepository,
+) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>> {
+    let preference_key = token::get_preference_key(claims);
+    preferences.update_preferences(&preference_key, patch).await
+}
+
+pub async fn save_user_theme(
+    claims: &token::Claims,
+    theme: &str,
+    preferences: &dyn PreferencesRepository,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let preference_key = token::get_preference_key(claims);
+    preferences.save_theme(&preference_key, theme).await
+}
+
+#[cfg(test)]
+pub use memory::InMemoryPreferencesRepository;
+
+#[cfg(test)]
+mod memory {
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::sync::RwLock;
+
+    use super::PreferencesRepository;
+    use crate::preference_schema::PreferenceMap;
+
+    #[derive(Default)]
+    pub struct InMemoryPreferencesRepository {
+        rows: RwLock<HashMap<String, PreferenceMap>>,
+    }
+
+    #[async_trait]
+    impl PreferencesRepository for InMemoryPreferencesRepository {
+        async fn get_theme(&self, preference_key: &str) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+            let rows = self.rows.read().unwrap();
+            let theme = rows.get(preference_key).and_then(|row| row.get("theme")).and_then(|theme| theme.as_str());
+            Ok(theme.map(str::to_string))
+        }
+
+        async fn save_theme(&self, preference_key: &str, theme: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            let mut rows = self.rows.write().unwrap();
+            rows.entry(preference_key.to_string()).or_default().insert("theme".to_string(), theme.into());
+            Ok(())
+        }
+
+        async fn get_preferences(&self, preference_key: &str) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(self.rows.read().unwrap().get(preference_key).cloned().unwrap_or_default())
+        }
+
+        async fn update_preferences(&self, preference_key: &str, patch: &PreferenceMap) -> Result<PreferenceMap, Box<dyn std::error::Error + Send + Sync>> {
+            let mut rows = self.rows.write().unwrap();
+            let row = rows.entry(preference_key.to_string()).or_default();
+            for (key, value) in patch {
+                if value.is_null() {
+                    row.remove(key);
+                } else {
+                    row.insert(key.clone(), value.clone());
+                }
+            }
+            Ok(row.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_anonymous_visitor_gets_default_theme() {
+        let preferences = InMemoryPreferencesRepository::default();
+
+        let resolved = resolve_theme(&UserContext::Anonymous, ThemeStorage::Both, &preferences, &HeaderMap::new()).await;
+        assert_eq!(resolved.theme, theme::default_theme());
+        assert!(resolved.follows_system);
+    }
+
+    #[tokio::test]
+    async fn test_saved_theme_round_trip() {
+        let preferences = InMemoryPreferencesRepository::default();
+        let token = token::generate_anonymous_token().unwrap();
+        let claims = token::verify_token(&token).unwrap();
+
+        save_user_theme(&claims, "light", &preferences).await.unwrap();
+
+        let context = UserContext::Authenticated(claims);
+        let resolved = resolve_theme(&context, ThemeStorage::Database, &preferences, &HeaderMap::new()).await;
+        assert_eq!(resolved.theme, "light");
+        assert!(!resolved.follows_system);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_theme_precedence() {
+        let preferences = InMemoryPreferencesRepository::default();
+        let token = token::generate_anonymous_token().unwrap();
+        let claims = token::verify_token(&token).unwrap();
+        let claimed = token::verify_token(&token::reissue_token(&claims, Some("light")).unwrap()).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CLIENT_HINT_HEADER, "\"light\"".parse().unwrap());
+        let hinted = resolve_theme(&UserContext::Anonymous, ThemeStorage::Both, &preferences, &headers).await;
+        assert_eq!(hinted.theme, "light");
+
+        let context = UserContext::Authenticated(claimed.clone());
+        let from_token = resolve_theme(&context, ThemeStorage::Token, &preferences, &HeaderMap::new()).await;
+        assert_eq!(from_token.theme, "light");
+
+        save_user_theme(&claimed, "dark", &preferences).await.unwrap();
+        let from_database = resolve_theme(&context, ThemeStorage::Both, &preferences, &headers).await;
+        assert_eq!(from_database.theme, "dark");
+    }
+
+    #[tokio::test]
+    async fn test_system_theme_follows_hint() {
+        let preferences = InMemoryPreferencesRepository::default();
+        let token = token::generate_anonymous_token().unwrap();
+        let claims = token::verify_token(&token).unwrap();
+        save_user_theme(&claims, SYSTEM_THEME, &preferences).await.unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CLIENT_HINT_HEADER, "\"light\"".parse().unwrap());
+
+        let context = UserContext::Authenticated(claims);
+        let resolved = resolve_theme(&context, ThemeStorage::Database, &preferences, &headers).await;
+        assert_eq!(resolved.theme, "light");
+        assert!(resolved.follows_system);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_theme_falls_back_to_hint() {
+        let preferences = InMemoryPreferencesRepository::default();
+        let token = token::generate_anonymous_token().unwrap();
+        let claims = token::verify_token(&token).unwrap();
+        preferences.save_theme(&token::get_preference_key(&claims), "neon").await.unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CLIENT_HINT_HEADER, "\"light\"".parse().unwrap());
+
+        let context = UserContext::Authenticated(claims);
+        let resolved = resolve_theme(&context, ThemeStorage::Database, &preferences, &headers).await;
+        assert_eq!(resolved.theme, "light");
+        assert!(resolved.follows_system);
+    }
+
+    #[tokio::test]
+    async fn test_preference_patch_sets_and_resets() {
+        let preferences = InMemoryPreferencesRepository::default();
+        let token = token::generate_anonymous_token().unwrap();
+        let claims = token::verify_token(&token).unwrap();
+
+        let patch = serde_json::json!({ "theme": "light", "font_size": 18 });
+        update_user_preferences(&claims, patch.as_object().unwrap(), &preferences).await.unwrap();
+
+        let patch = serde_json::json!({ "font_size": null });
+        let stored = update_user_preferences(&claims, patch.as_object().unwrap(), &preferences).await.unwrap();
+        assert_eq!(stored.get("font_size"), None);
+        assert_eq!(get_user_preferences(&claims, &preferences).await.unwrap()["theme"], "light");
+        assert_eq!(preferences.get_theme(&token::get_preference_key(&claims)).await.unwrap().as_deref(), Some("light"));
+    }
+}
@@ -0,0 +1,382 @@
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::database::DbPool;
+use crate::error::ApiError;
+use crate::middleware::UserContext;
+use crate::security::CSP_REPORT_PATH;
+use crate::token;
+
+pub type SharedRateLimiter = Arc<RateLimiter>;
+
+const DEFAULT_WRITE_BURST: f64 = 30.0;
+const DEFAULT_WRITE_PER_MINUTE: f64 = 60.0;
+const DEFAULT_KEY_WRITE_BURST: f64 = 10.0;
+const DEFAULT_KEY_WRITE_PER_MINUTE: f64 = 20.0;
+const DEFAULT_MINT_BURST: f64 = 5.0;
+const DEFAULT_MINT_PER_MINUTE: f64 = 2.0;
+// idle buckets are full again long before this, so dropping them changes nothing
+const IDLE_BUCKET_AGE: Duration = Duration::from_secs(60 * 60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+impl Limit {
+    pub fn per_minute(burst: f64, per_minute: f64) -> Self {
+        Self { burst, per_second: per_minute / 60.0 }
+    }
+
+    // reads {prefix}_BURST and {prefix}_PER_MINUTE
+    fn from_env(prefix: &str, burst: f64, per_minute: f64) -> Self {
+        let read = |name: &str, default: f64| {
+            env::var(format!("{}_{}", prefix, name))
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|value: &f64| *value > 0.0)
+                .unwrap_or(default)
+        };
+
+        Self::per_minute(read("BURST", burst), read("PER_MINUTE", per_minute))
+    }
+
+    // how long until the bucket holds a whole token again
+    fn wait_for(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64(((1.0 - tokens) / self.per_second).max(0.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+// token buckets keyed by strings like "write:ip:1.2.3.4", one request takes one token
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    async fn take(&self, key: &str, limit: Limit) -> Result<Decision, Box<dyn std::error::Error + Send + Sync>>;
+
+    // gives back a token that was taken for a request another bucket refused
+    async fn refund(&self, key: &str, limit: Limit) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn prune(&self, idle: Duration) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// enough for a single instance, every instance counts on its own
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryStore {
+    fn take_at(&self, key: &str, limit: Limit, now: Instant) -> Decision {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket { tokens: limit.burst, updated: now });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Decision::Allowed
+        } else {
+            Decision::Limited { retry_after: limit.wait_for(bucket.tokens) }
+        }
+    }
+
+    fn refund_at(&self, key: &str, limit: Limit) {
+        if let Some(bucket) = self.buckets.lock().unwrap().get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(limit.burst);
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn take(&self, key: &str, limit: Limit) -> Result<Decision, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.take_at(key, limit, Instant::now()))
+    }
+
+    async fn refund(&self, key: &str, limit: Limit) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.refund_at(key, limit);
+        Ok(())
+    }
+
+    async fn prune(&self, idle: Duration) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.buckets.lock().unwrap().retain(|_, bucket| bucket.updated.elapsed() < idle);
+        Ok(())
+    }
+}
+
+// the same buckets in an unlogged table, shared by every instance behind the proxy
+pub struct PgStore {
+    db_pool: DbPool,
+}
+
+impl PgStore {
+    pub fn new(db_pool: DbPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+// $2 burst, $3 tokens per second. the refill is computed and spent in one statement so
+// concurrent requests from several instances can't both take the last token
+const TAKE_TOKEN: &str = "INSERT INTO rate_limit_buckets (bucket_key, tokens, allowed, updated_at)
+     VALUES ($1, $2::float8 - 1, TRUE, NOW())
+     ON CONFLICT (bucket_key) DO UPDATE SET
+         tokens = CASE
+             WHEN LEAST($2::float8, rate_limit_buckets.tokens + EXTRACT(EPOCH FROM NOW() - rate_limit_buckets.updated_at)::float8 * $3::float8) >= 1
+             THEN LEAST($2::float8, rate_limit_buckets.tokens + EXTRACT(EPOCH FROM NOW() - rate_limit_buckets.updated_at)::float8 * $3::float8) - 1
+             ELSE LEAST($2::float8, rate_limit_buckets.tokens + EXTRACT(EPOCH FROM NOW() - rate_limit_buckets.updated_at)::float8 * $3::float8)
+         END,
+         allowed = LEAST($2::float8, rate_limit_buckets.tokens + EXTRACT(EPOCH FROM NOW() - rate_limit_buckets.updated_at)::float8 * $3::float8) >= 1,
+         updated_at = NOW()
+     RETURNING allowed, tokens";
+const REFUND_TOKEN: &str = "UPDATE rate_limit_buckets SET tokens = LEAST($2::float8, tokens + 1) WHERE bucket_key = $1";
+const PRUNE_BUCKETS: &str = "DELETE FROM rate_limit_buckets WHERE updated_at < NOW() - make_interval(secs => $1)";
+
+#[async_trait]
+impl RateLimitStore for PgStore {
+    async fn take(&self, key: &str, limit: Limit) -> Result<Decision, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(TAKE_TOKEN).await?;
+        let row = conn.query_one(&statement, &[&key, &limit.burst, &limit.per_second]).await?;
+
+        if row.get("allowed") {
+            Ok(Decision::Allowed)
+        } else {
+            Ok(Decision::Limited { retry_after: limit.wait_for(row.get("tokens")) })
+        }
+    }
+
+    async fn refund(&self, key: &str, limit: Limit) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(REFUND_TOKEN).await?;
+        conn.execute(&statement, &[&key, &limit.burst]).await?;
+        Ok(())
+    }
+
+    async fn prune(&self, idle: Duration) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.db_pool.get().await?;
+        let statement = conn.prepare_cached(PRUNE_BUCKETS).await?;
+        conn.execute(&statement, &[&idle.as_secs_f64()]).await?;
+        Ok(())
+    }
+}
+
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    // every state-changing request, per client ip
+    writes: Limit,
+    // every state-changing request, per preference key
+    key_writes: Limit,
+    // writes without a token, each of which mints one and may create a row
+    mints: Limit,
+    // take the client ip from X-Forwarded-For, only safe behind a proxy that sets it
+    trust_proxy: bool,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>, writes: Limit, key_writes: Limit, mints: Limit, trust_proxy: bool) -> Self {
+        Self { store, writes, key_writes, mints, trust_proxy }
+    }
+
+    // RATE_LIMIT_STORE=postgres shares the buckets between instances, memory is the default
+    pub fn from_env(db_pool: DbPool) -> Self {
+        let store: Arc<dyn RateLimitStore> = match env::var("RATE_LIMIT_STORE").as_deref() {
+            Ok("postgres") => Arc::new(PgStore::new(db_pool)),
+            _ => Arc::new(MemoryStore::default()),
+        };
+
+        Self::new(
+            store,
+            Limit::from_env("RATE_LIMIT_WRITES", DEFAULT_WRITE_BURST, DEFAULT_WRITE_PER_MINUTE),
+            Limit::from_env("RATE_LIMIT_KEY_WRITES", DEFAULT_KEY_WRITE_BURST, DEFAULT_KEY_WRITE_PER_MINUTE),
+            Limit::from_env("RATE_LIMIT_MINTS", DEFAULT_MINT_BURST, DEFAULT_MINT_PER_MINUTE),
+            env::var("RATE_LIMIT_TRUST_PROXY").is_ok_and(|value| value == "1" || value == "true"),
+        )
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        if self.trust_proxy {
+            // the proxy appends the address it saw, so the last entry is the one it vouches for
+            let forwarded = headers.get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok());
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+        peer
+    }
+
+    // the buckets a request takes from, all of them have to allow it
+    // `may_mint` is false for requests that never get a token, like csp reports
+    fn buckets(&self, ip: Option<IpAddr>, user_context: Option<&UserContext>, may_mint: bool) -> Vec<(String, Limit)> {
+        let mut buckets = Vec::new();
+        if let Some(ip) = ip {
+            buckets.push((format!("write:ip:{}", ip), self.writes));
+        }
+
+        match user_context.and_then(UserContext::get_claims) {
+            Some(claims) => buckets.push((format!("write:key:{}", token::get_preference_key(claims)), self.key_writes)),
+            None if may_mint => {
+                if let Some(ip) = ip {
+                    buckets.push((format!("mint:ip:{}", ip), self.mints));
+                }
+            }
+            None => {}
+        }
+
+        buckets
+    }
+
+    // a refused request costs nothing, tokens already taken from earlier buckets go back
+    pub async fn check(&self, ip: Option<IpAddr>, user_context: Option<&UserContext>, may_mint: bool) -> Decision {
+        let mut taken = Vec::new();
+        for (key, limit) in self.buckets(ip, user_context, may_mint) {
+            match self.store.take(&key, limit).await {
+                Ok(Decision::Allowed) => taken.push((key, limit)),
+                Ok(limited) => {
+                    for (key, limit) in taken {
+                        if let Err(e) = self.store.refund(&key, limit).await {
+                            eprintln!("rate limit refund failed: {}", e);
+                        }
+                    }
+                    return limited;
+                }
+                // an unavailable store shouldn't take the site down with it
+                Err(e) => eprintln!("rate limit check failed, allowing request: {}", e),
+            }
+        }
+        Decision::Allowed
+    }
+}
+
+// limits state-changing requests, runs inside jwt_cookie_middleware so tokenless clients are known
+pub async fn rate_limit(State(limiter): State<SharedRateLimiter>, req: Request, next: Next) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip());
+    let ip = limiter.client_ip(req.headers(), peer);
+
+    let may_mint = req.uri().path() != CSP_REPORT_PATH;
+
+    match limiter.check(ip, req.extensions().get::<UserContext>(), may_mint).await {
+        Decision::Allowed => next.run(req).await,
+        Decision::Limited { retry_after } => ApiError::rate_limited(retry_after).into_response(),
+    }
+}
+
+// drops buckets nobody has touched in a while so the store doesn't grow without bound
+pub fn spawn_pruner(limiter: SharedRateLimiter) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = limiter.store.prune(IDLE_BUCKET_AGE).await {
+                eprintln!("rate limit pruning failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let store = MemoryStore::default();
+        let limit = Limit::per_minute(2.0, 60.0);
+        let start = Instant::now();
+
+        assert_eq!(store.take_at("a", limit, start), Decision::Allowed);
+        assert_eq!(store.take_at("a", limit, start), Decision::Allowed);
+        let Decision::Limited { retry_after } = store.take_at("a", limit, start) else {
+            panic!("third request should be limited");
+        };
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        assert_eq!(store.take_at("b", limit, start), Decision::Allowed);
+        assert_eq!(store.take_at("a", limit, start + Duration::from_secs(1)), Decision::Allowed);
+    }
+
+    #[tokio::test]
+    async fn test_tokenless_writes_are_minting() {
+        let limiter = RateLimiter::new(
+            Arc::new(MemoryStore::default()),
+            Limit::per_minute(10.0, 1.0),
+            Limit::per_minute(10.0, 1.0),
+            Limit::per_minute(1.0, 1.0),
+            false,
+        );
+        let ip = Some(IpAddr::from([203, 0, 113, 7]));
+
+        assert_eq!(limiter.check(ip, Some(&UserContext::Anonymous), true).await, Decision::Allowed);
+        assert!(matches!(limiter.check(ip, Some(&UserContext::Anonymous), true).await, Decision::Limited { .. }));
+
+        let claims = token::verify_token(&token::generate_anonymous_token().unwrap()).unwrap();
+        assert_eq!(limiter.check(ip, Some(&UserContext::Authenticated(claims)), true).await, Decision::Allowed);
+    }
+
+    #[tokio::test]
+    async fn test_refused_requests_are_refunded() {
+        let limiter = RateLimiter::new(
+            Arc::new(MemoryStore::default()),
+            Limit::per_minute(2.0, 1.0),
+            Limit::per_minute(1.0, 1.0),
+            Limit::per_minute(1.0, 1.0),
+            false,
+        );
+        let ip = Some(IpAddr::from([203, 0, 113, 7]));
+        let claims = token::verify_token(&token::generate_anonymous_token().unwrap()).unwrap();
+        let busy = UserContext::Authenticated(claims);
+
+        // the key bucket refuses the second request, which must not drain the shared ip bucket
+        assert_eq!(limiter.check(ip, Some(&busy), true).await, Decision::Allowed);
+        assert!(matches!(limiter.check(ip, Some(&busy), true).await, Decision::Limited { .. }));
+        assert!(matches!(limiter.check(ip, Some(&busy), true).await, Decision::Limited { .. }));
+
+        let claims = token::verify_token(&token::generate_anonymous_token().unwrap()).unwrap();
+        assert_eq!(limiter.check(ip, Some(&UserContext::Authenticated(claims)), true).await, Decision::Allowed);
+    }
+
+    #[test]
+    fn test_forwarded_ip_needs_trust() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "198.51.100.1, 203.0.113.9".parse().unwrap());
+        let peer = Some(IpAddr::from([127, 0, 0, 1]));
+
+        let direct = RateLimiter::new(Arc::new(MemoryStore::default()), Limit::per_minute(1.0, 1.0), Limit::per_minute(1.0, 1.0), Limit::per_minute(1.0, 1.0), false);
+        assert_eq!(direct.client_ip(&headers, peer), peer);
+
+        let proxied = RateLimiter { trust_proxy: true, ..direct };
+        assert_eq!(proxied.client_ip(&headers, peer), Some(IpAddr::from([203, 0, 113, 9])));
+    }
+}
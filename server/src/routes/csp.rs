@@ -0,0 +1,39 @@
+use axum::{body::Bytes, http::StatusCode};
+use serde_json::Value;
+
+// reports are small, anything bigger is not from a browser
+const MAX_REPORT_BYTES: usize = 16 * 1024;
+
+// browsers post `{"csp-report": {...}}` for report-uri and a list of reports for report-to,
+// with kebab-case and camelCase field names respectively
+pub async fn report(body: Bytes) -> StatusCode {
+    if body.len() > MAX_REPORT_BYTES {
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+
+    let Ok(report) = serde_json::from_slice::<Value>(&body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    let violations: Vec<&Value> = match &report {
+        Value::Array(reports) => reports.iter().filter_map(|report| report.get("body")).collect(),
+        report => report.get("csp-report").into_iter().collect(),
+    };
+
+    for violation in violations {
+        let field = |names: [&str; 2]| {
+            names.iter()
+                .find_map(|name| violation.get(*name).and_then(Value::as_str))
+                .unwrap_or("-")
+        };
+
+        println!(
+            "csp violation: {} blocked {} on {}",
+            field(["effective-directive", "effectiveDirective"]),
+            field(["blocked-uri", "blockedURL"]),
+            field(["document-uri", "documentURL"]),
+        );
+    }
+
+    StatusCode::NO_CONTENT
+}
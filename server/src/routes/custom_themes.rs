@@ -0,0 +1,117 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tokio_postgres::error::SqlState;
+
+use crate::custom_themes::{CustomTheme, PaletteRequest, SharedCustomThemes};
+use crate::error::{ApiError, ApiJson};
+use crate::middleware::UserContext;
+use crate::preferences::{self, SharedPreferences};
+use crate::token::{self, Claims};
+
+// palettes belong to signed-in users only, anonymous visitors get the built-in themes
+fn require_user(user_context: &UserContext) -> Result<(String, &Claims), ApiError> {
+    user_context
+        .get_claims()
+        .filter(|claims| claims.user_id.is_some())
+        .map(|claims| (token::get_preference_key(claims), claims))
+        .ok_or_else(|| ApiError::unauthorized("sign in to manage custom themes"))
+}
+
+fn not_found() -> ApiError {
+    ApiError::not_found("custom_theme_not_found", "custom theme not found")
+}
+
+// duplicate names are the user's mistake, anything else is ours
+fn save_error(e: Box<dyn std::error::Error + Send + Sync>, palette: &PaletteRequest) -> ApiError {
+    let unique_violation = e
+        .downcast_ref::<tokio_postgres::Error>()
+        .and_then(|e| e.code())
+        .is_some_and(|code| *code == SqlState::UNIQUE_VIOLATION);
+
+    if unique_violation {
+        ApiError::conflict("duplicate_name", format!("a custom theme named '{}' already exists", palette.name.trim()))
+    } else {
+        ApiError::storage(e)
+    }
+}
+
+pub async fn list(
+    Extension(user_context): Extension<UserContext>,
+    Extension(custom_themes): Extension<SharedCustomThemes>,
+) -> Result<Json<Vec<CustomTheme>>, ApiError> {
+    let (preference_key, _) = require_user(&user_context)?;
+
+    let themes = custom_themes.list(&preference_key).await.map_err(ApiError::storage)?;
+    Ok(Json(themes))
+}
+
+pub async fn create(
+    Extension(user_context): Extension<UserContext>,
+    Extension(custom_themes): Extension<SharedCustomThemes>,
+    ApiJson(palette): ApiJson<PaletteRequest>,
+) -> Result<Response, ApiError> {
+    let (preference_key, _) = require_user(&user_context)?;
+    palette.validate().map_err(|message| ApiError::bad_request("invalid_palette", message))?;
+
+    let theme = custom_themes.create(&preference_key, &palette).await.map_err(|e| save_error(e, &palette))?;
+    Ok((StatusCode::CREATED, Json(theme)).into_response())
+}
+
+pub async fn update(
+    Path(id): Path<i32>,
+    Extension(user_context): Extension<UserContext>,
+    Extension(custom_themes): Extension<SharedCustomThemes>,
+    ApiJson(palette): ApiJson<PaletteRequest>,
+) -> Result<Json<CustomTheme>, ApiError> {
+    let (preference_key, _) = require_user(&user_context)?;
+    palette.validate().map_err(|message| ApiError::bad_request("invalid_palette", message))?;
+
+    let theme = custom_themes.update(&preference_key, id, &palette).await.map_err(|e| save_error(e, &palette))?;
+    theme.map(Json).ok_or_else(not_found)
+}
+
+pub async fn delete(
+    Path(id): Path<i32>,
+    Extension(user_context): Extension<UserContext>,
+    Extension(custom_themes): Extension<SharedCustomThemes>,
+) -> Result<StatusCode, ApiError> {
+    let (preference_key, _) = require_user(&user_context)?;
+
+    match custom_themes.delete(&preference_key, id).await.map_err(ApiError::storage)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(not_found()),
+    }
+}
+
+// switch to the palette's base theme and overlay the palette on it
+pub async fn activate(
+    Path(id): Path<i32>,
+    Extension(user_context): Extension<UserContext>,
+    Extension(custom_themes): Extension<SharedCustomThemes>,
+    Extension(preferences): Extension<SharedPreferences>,
+) -> Result<Json<CustomTheme>, ApiError> {
+    let (preference_key, claims) = require_user(&user_context)?;
+
+    let theme = custom_themes.get(&preference_key, id).await
+        .map_err(ApiError::storage)?
+        .ok_or_else(not_found)?;
+
+    preferences::save_user_theme(claims, &theme.base_theme, preferences.as_ref()).await.map_err(ApiError::storage)?;
+    custom_themes.set_active(&preference_key, Some(theme.id)).await.map_err(ApiError::storage)?;
+
+    Ok(Json(theme))
+}
+
+pub async fn deactivate(
+    Extension(user_context): Extension<UserContext>,
+    Extension(custom_themes): Extension<SharedCustomThemes>,
+) -> Result<StatusCode, ApiError> {
+    let (preference_key, _) = require_user(&user_context)?;
+
+    custom_themes.set_active(&preference_key, None).await.map_err(ApiError::storage)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -12,9 +12,13 @@ static ICONS: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
     let mut icons = HashMap::new();
     icons.insert("sun", r#"<svg xmlns="http://www.w3.org/2000/svg" width="24" height="24" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round" class="lucide lucide-sun-icon lucide-sun"><circle cx="12" cy="12" r="4"/><path d="M12 2v2"/><path d="M12 20v2"/><path d="m4.93 4.93 1.41 1.41"/><path d="m17.66 17.66 1.41 1.41"/><path d="M2 12h2"/><path d="M20 12h2"/><path d="m6.34 17.66-1.41 1.41"/><path d="m19.07 4.93-1.41 1.41"/></svg>"#);
     icons.insert("moon", r#"<svg xmlns="http://www.w3.org/2000/svg" width="24" height="24" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round" class="lucide lucide-moon"><path d="M12 3a6 6 0 0 0 9 9 9 9 0 1 1-9-9Z"/></svg>"#);
+    icons.insert("monitor", r#"<svg xmlns="http://www.w3.org/2000/svg" width="24" height="24" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round" class="lucide lucide-monitor"><rect width="20" height="14" x="2" y="3" rx="2"/><line x1="8" x2="16" y1="21" y2="21"/><line x1="12" x2="12" y1="17" y2="21"/></svg>"#);
     icons
 });
 
+// classes of the toggle icon rendered into base.html
+pub const DEFAULT_CLASSES: &str = "text-foreground w-6 h-6";
+
 #[derive(Debug, Deserialize)]
 pub struct IconQuery {
     classes: Option<String>,
@@ -28,15 +32,19 @@ pub async fn get_icon(
         None => return (StatusCode::NOT_FOUND, Html("Icon not found".to_string())).into_response(),
     };
 
-    let styled_svg = add_classes_to_svg(&svg_content, &params.classes.unwrap_or_default());
+    let styled_svg = add_classes_to_svg(svg_content, &params.classes.unwrap_or_default());
     
     Html(styled_svg.into_owned()).into_response()
 }
 
-fn get_svg_content(icon_name: &str) -> Option<&'static str> {
+pub fn get_svg_content(icon_name: &str) -> Option<&'static str> {
     ICONS.get(icon_name).copied()
 }
 
+pub fn render_icon(icon_name: &str, classes: &str) -> Option<Cow<'static, str>> {
+    get_svg_content(icon_name).map(|svg| add_classes_to_svg(svg, classes))
+}
+
 fn add_classes_to_svg<'a>(svg_content: &'a str, classes: &str) -> Cow<'a, str> {
     if classes.is_empty() {
         return Cow::Borrowed(svg_content);
@@ -1,3 +1,6 @@
 pub mod pages;
 pub mod themes;
-pub mod icons;
\ No newline at end of file
+pub mod icons;
+pub mod custom_themes;
+pub mod preferences;
+pub mod csp;
@@ -1,74 +1,92 @@
 use axum::{
-    extract::Extension,
-    http::{StatusCode, Uri},
+    extract::{rejection::ExtensionRejection, Extension, FromRequestParts},
+    http::{request::Parts, HeaderMap, StatusCode, Uri},
     response::IntoResponse,
 };
 use askama::Template;
 use askama_web::WebTemplate;
-use std::borrow::Cow;
 
-use crate::database::DbPool;
+use crate::assets::AssetHelpers;
+use crate::csrf::CsrfToken;
+use crate::custom_themes::SharedCustomThemes;
 use crate::middleware::UserContext;
+use crate::preferences::{self, SharedPreferences, ThemeStorage};
+use crate::security::CspNonce;
+use crate::theme::ThemeView;
 use crate::token;
-use crate::DEFAULT_THEME;
 
 #[derive(Template, WebTemplate)]
 #[template(path = "index.html")]
 pub struct IndexTemplate {
-    pub theme: String,
+    pub theme: ThemeView,
+    pub csrf: CsrfToken,
+    pub csp_nonce: CspNonce,
 }
 
 #[derive(Template, WebTemplate)]
 #[template(path = "error.html")]
 pub struct ErrorTemplate {
-    pub theme: String,
+    pub theme: ThemeView,
+    pub csrf: CsrfToken,
+    pub csp_nonce: CspNonce,
     pub requested_path: String,
 }
 
-pub async fn index(
-    Extension(user_context): Extension<UserContext>,
-    Extension(db_pool): Extension<DbPool>,
-) -> IndexTemplate {
-    let theme = get_user_theme(&user_context, &db_pool).await
-        .unwrap_or(Cow::Borrowed(DEFAULT_THEME));
-    
+// what every page needs to render base.html, taken from the extensions the middleware adds
+pub struct PageContext {
+    user_context: UserContext,
+    preferences: SharedPreferences,
+    storage: ThemeStorage,
+    custom_themes: SharedCustomThemes,
+    csrf: CsrfToken,
+    csp_nonce: CspNonce,
+    headers: HeaderMap,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for PageContext {
+    type Rejection = ExtensionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(user_context) = Extension::from_request_parts(parts, state).await?;
+        let Extension(preferences) = Extension::from_request_parts(parts, state).await?;
+        let Extension(storage) = Extension::from_request_parts(parts, state).await?;
+        let Extension(custom_themes) = Extension::from_request_parts(parts, state).await?;
+        let Extension(csrf) = Extension::from_request_parts(parts, state).await?;
+        let Extension(csp_nonce) = Extension::from_request_parts(parts, state).await?;
+
+        Ok(PageContext { user_context, preferences, storage, custom_themes, csrf, csp_nonce, headers: parts.headers.clone() })
+    }
+}
+
+pub async fn index(page: PageContext) -> IndexTemplate {
     IndexTemplate { 
-        theme: theme.into_owned()
+        theme: theme_view(&page).await,
+        csrf: page.csrf,
+        csp_nonce: page.csp_nonce,
     }
 }
 
-pub async fn not_found(
-    uri: Uri,
-    Extension(user_context): Extension<UserContext>,
-    Extension(db_pool): Extension<DbPool>,
-) -> impl IntoResponse {
-    let theme = get_user_theme(&user_context, &db_pool).await
-        .unwrap_or(Cow::Borrowed(DEFAULT_THEME));
-    
+pub async fn not_found(uri: Uri, page: PageContext) -> impl IntoResponse {
     (StatusCode::NOT_FOUND, ErrorTemplate { 
-        theme: theme.into_owned(),
+        theme: theme_view(&page).await,
+        csrf: page.csrf,
+        csp_nonce: page.csp_nonce,
         requested_path: uri.path().to_string(),
     })
 }
 
-async fn get_user_theme(
-    user_context: &UserContext,
-    db_pool: &DbPool,
-) -> Result<Cow<'static, str>, Box<dyn std::error::Error + Send + Sync>> {
-    if let Some(claims) = user_context.get_claims() {
-        let preference_key = token::get_preference_key(claims);
-        
-        let conn = db_pool.get().await?;
-        let rows = conn
-            .query("SELECT theme FROM user_preferences WHERE preference_key = $1", &[&preference_key])
-            .await?;
+async fn theme_view(page: &PageContext) -> ThemeView {
+    let resolved = preferences::resolve_theme(&page.user_context, page.storage, page.preferences.as_ref(), &page.headers).await;
+    let mut view = ThemeView::new(resolved.theme.into_owned(), resolved.follows_system);
 
-        if let Some(row) = rows.first() {
-            let theme: String = row.get("theme");
-            return Ok(Cow::Owned(theme));
+    // palettes belong to signed-in users and live in the database, nobody else needs the lookup
+    let signed_in = page.user_context.get_claims().filter(|claims| claims.user_id.is_some());
+    if page.storage.uses_database() && let Some(claims) = signed_in {
+        match page.custom_themes.active(&token::get_preference_key(claims)).await {
+            Ok(active) => view.custom_css = active.map(|theme| theme.to_css()),
+            Err(e) => eprintln!("custom theme lookup failed: {}", e),
         }
     }
 
-    // default theme for new/anonymous users
-    Ok(Cow::Borrowed(DEFAULT_THEME))
-} 
\ No newline at end of file
+    view
+}
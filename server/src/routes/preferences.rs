@@ -0,0 +1,121 @@
+use axum::{extract::Extension, Json};
+use serde::Serialize;
+use tower_cookies::Cookies;
+
+use crate::error::{ApiError, ApiJson};
+use crate::middleware::UserContext;
+use crate::preference_schema::{self, PreferenceDefinition, PreferenceMap, PREFERENCES};
+use crate::preferences::{self, PreferencesRepository, SharedPreferences, ThemeStorage};
+use crate::routes::themes::{ensure_user_token, set_auth_cookie};
+use crate::token;
+
+#[derive(Debug, Serialize)]
+pub struct PreferencesResponse {
+    pub success: bool,
+    pub preferences: PreferenceMap,
+    pub schema: &'static [PreferenceDefinition],
+}
+
+fn preferences_response(stored: &PreferenceMap) -> Json<PreferencesResponse> {
+    Json(PreferencesResponse {
+        success: true,
+        preferences: preference_schema::effective(stored),
+        schema: &PREFERENCES,
+    })
+}
+
+// what the visitor has chosen so far, without defaults filled in
+async fn stored_preferences(
+    user_context: &UserContext,
+    storage: ThemeStorage,
+    preferences: &dyn PreferencesRepository,
+) -> PreferenceMap {
+    let Some(claims) = user_context.get_claims() else {
+        return PreferenceMap::new();
+    };
+
+    let mut stored = PreferenceMap::new();
+    if storage.uses_database() {
+        match preferences::get_user_preferences(claims, preferences).await {
+            Ok(preferences) => stored = preferences,
+            Err(e) => eprintln!("failed to load preferences: {}", e),
+        }
+    }
+
+    if storage.uses_token() && !stored.contains_key("theme") && let Some(theme) = &claims.theme {
+        stored.insert("theme".to_string(), theme.clone().into());
+    }
+
+    stored
+}
+
+// validate and store a patch, returns the stored preferences afterwards.
+// the token only has room for the theme, so other keys need the database
+pub async fn apply_patch(
+    user_context: &UserContext,
+    cookies: &Cookies,
+    storage: ThemeStorage,
+    preferences: &dyn PreferencesRepository,
+    patch: &PreferenceMap,
+) -> Result<PreferenceMap, ApiError> {
+    preference_schema::validate_patch(patch)
+        .map_err(|message| ApiError::bad_request("invalid_preference", message))?;
+
+    let theme_only = patch.keys().all(|key| key == "theme");
+    if !storage.uses_database() && !theme_only {
+        return Err(ApiError::bad_request("unsupported_preference", "only the theme can be saved without a database"));
+    }
+
+    let claims = ensure_user_token(user_context, cookies).await.map_err(ApiError::internal)?;
+
+    // the token claim is written first so a theme choice survives a failed database write
+    let mut token_saved = false;
+    let mut failure = None;
+    if storage.uses_token() && let Some(theme) = patch.get("theme") {
+        match token::reissue_token(&claims, theme.as_str()) {
+            Ok(new_token) => {
+                set_auth_cookie(cookies, &new_token);
+                token_saved = true;
+            }
+            Err(e) => failure = Some(ApiError::internal(e)),
+        }
+    }
+
+    if storage.uses_database() {
+        match preferences::update_user_preferences(&claims, patch, preferences).await {
+            Ok(stored) => return Ok(stored),
+            Err(e) => failure = Some(ApiError::storage(e)),
+        }
+    }
+
+    // the database is off or down, report what the token holds now
+    if token_saved && theme_only {
+        let mut stored = PreferenceMap::new();
+        if let Some(theme) = patch.get("theme").filter(|theme| !theme.is_null()) {
+            stored.insert("theme".to_string(), theme.clone());
+        }
+        return Ok(stored);
+    }
+
+    Err(failure.unwrap_or_else(|| ApiError::internal("preferences were not saved")))
+}
+
+pub async fn get_preferences(
+    Extension(user_context): Extension<UserContext>,
+    Extension(preferences): Extension<SharedPreferences>,
+    Extension(storage): Extension<ThemeStorage>,
+) -> Json<PreferencesResponse> {
+    let stored = stored_preferences(&user_context, storage, preferences.as_ref()).await;
+    preferences_response(&stored)
+}
+
+pub async fn patch_preferences(
+    cookies: Cookies,
+    Extension(user_context): Extension<UserContext>,
+    Extension(preferences): Extension<SharedPreferences>,
+    Extension(storage): Extension<ThemeStorage>,
+    ApiJson(patch): ApiJson<PreferenceMap>,
+) -> Result<Json<PreferencesResponse>, ApiError> {
+    let stored = apply_patch(&user_context, &cookies, storage, preferences.as_ref(), &patch).await?;
+    Ok(preferences_response(&stored))
+}
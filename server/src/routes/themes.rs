@@ -1,17 +1,25 @@
+use askama::Template;
+use askama_web::WebTemplate;
 use axum::{
-    extract::{Extension, Form},
-    response::{IntoResponse},
+    extract::{Extension, Form, FromRequest, Request},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
     Json,
 };
 use tower_cookies::{Cookie, Cookies};
 use serde::{Deserialize, Serialize};
 use time::Duration;
-use std::borrow::Cow;
 
-use crate::database::DbPool;
+use crate::error::ApiError;
 use crate::middleware::UserContext;
+use crate::preference_schema::PreferenceMap;
+use crate::preferences::{self, SharedPreferences, ThemeStorage};
+use crate::routes;
 use crate::token;
-use crate::DEFAULT_THEME;
+use crate::theme::{self, ThemeView};
+
+const HX_REQUEST: &str = "hx-request";
+const HX_TRIGGER: &str = "hx-trigger";
 
 #[derive(Debug, Deserialize)]
 pub struct ThemeForm {
@@ -22,115 +30,101 @@ pub struct ThemeForm {
 pub struct ThemeResponse {
     pub theme: String,
     pub success: bool,
+    pub follows_system: bool,
 }
 
 pub async fn get_theme(
     Extension(user_context): Extension<UserContext>,
-    Extension(db_pool): Extension<DbPool>,
+    Extension(preferences): Extension<SharedPreferences>,
+    Extension(storage): Extension<ThemeStorage>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let theme = get_user_theme(&user_context, &db_pool).await
-        .unwrap_or(Cow::Borrowed(DEFAULT_THEME));
+    let resolved = preferences::resolve_theme(&user_context, storage, preferences.as_ref(), &headers).await;
 
     Json(ThemeResponse {
-        theme: theme.into_owned(),
+        theme: resolved.theme.into_owned(),
         success: true,
+        follows_system: resolved.follows_system,
     })
 }
 
-pub async fn set_theme(
-    cookies: Cookies,
-    Extension(user_context): Extension<UserContext>,
-    Extension(db_pool): Extension<DbPool>,
-    Form(form): Form<ThemeForm>,
-) -> impl IntoResponse {
-    if !matches!(form.theme.as_str(), "light" | "dark") {
-        return Json(ThemeResponse {
-            theme: DEFAULT_THEME.to_string(),
-            success: false,
-        }).into_response();
-    }
+// the toggle button alone, swapped in by htmx after a theme change
+#[derive(Template, WebTemplate)]
+#[template(path = "theme_toggle.html")]
+pub struct ThemeToggleTemplate {
+    pub theme: ThemeView,
+}
 
-    let claims = match ensure_user_token(&user_context, &cookies).await {
-        Ok(claims) => claims,
-        Err(_) => {
-            return Json(ThemeResponse {
-                theme: DEFAULT_THEME.to_string(),
-                success: false,
-            }).into_response();
-        }
-    };
+// a theme choice sent form-encoded, as the page does, or as a json body
+pub struct ThemeInput(pub ThemeForm);
 
-    match save_user_theme(&claims, &form.theme, &db_pool).await {
-        Ok(_) => {
-            Json(ThemeResponse {
-                theme: form.theme,
-                success: true,
-            }).into_response()
-        }
-        Err(_) => {
-            Json(ThemeResponse {
-                theme: DEFAULT_THEME.to_string(),
-                success: false,
-            }).into_response()
-        }
+impl<S: Send + Sync> FromRequest<S> for ThemeInput {
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = req.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+
+        let form = if is_json {
+            Json::<ThemeForm>::from_request(req, state).await.map(|Json(form)| form).map_err(|e| e.body_text())
+        } else {
+            Form::<ThemeForm>::from_request(req, state).await.map(|Form(form)| form).map_err(|e| e.body_text())
+        };
+
+        form.map(ThemeInput).map_err(|message| ApiError::bad_request("invalid_body", message))
     }
 }
 
-// get user theme from database or return default
-async fn get_user_theme(
-    user_context: &UserContext,
-    db_pool: &DbPool,
-) -> Result<Cow<'static, str>, Box<dyn std::error::Error + Send + Sync>> {
-    if let Some(claims) = user_context.get_claims() {
-        let preference_key = token::get_preference_key(claims);
-        
-        let conn = db_pool.get().await?;
-        let rows = conn
-            .query("SELECT theme FROM user_preferences WHERE preference_key = $1", &[&preference_key])
-            .await?;
-
-        if let Some(row) = rows.first() {
-            let theme: String = row.get("theme");
-            return Ok(Cow::Owned(theme));
-        }
+// kept for existing clients, a theme-only patch of /api/preferences
+pub async fn set_theme(
+    cookies: Cookies,
+    Extension(user_context): Extension<UserContext>,
+    Extension(preferences): Extension<SharedPreferences>,
+    Extension(storage): Extension<ThemeStorage>,
+    headers: HeaderMap,
+    ThemeInput(form): ThemeInput,
+) -> Result<Response, ApiError> {
+    let mut patch = PreferenceMap::new();
+    patch.insert("theme".to_string(), form.theme.clone().into());
+
+    routes::preferences::apply_patch(&user_context, &cookies, storage, preferences.as_ref(), &patch).await?;
+
+    let follows_system = form.theme == theme::SYSTEM_THEME;
+    if !headers.contains_key(HX_REQUEST) {
+        return Ok(Json(ThemeResponse {
+            theme: form.theme,
+            success: true,
+            follows_system,
+        }).into_response());
     }
 
-    Ok(Cow::Borrowed(DEFAULT_THEME))
-}
+    // htmx gets the new button and an event carrying the theme, one round trip for the whole switch
+    let name = if follows_system {
+        preferences::system_theme(&headers).to_string()
+    } else {
+        form.theme
+    };
+    let trigger = serde_json::json!({
+        "theme-changed": { "theme": name, "followsSystem": follows_system }
+    });
 
-async fn save_user_theme(
-    claims: &token::Claims,
-    theme: &str,
-    db_pool: &DbPool,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let preference_key = token::get_preference_key(claims);
-    
-    let conn = db_pool.get().await?;
-    
-    conn.execute(
-        "INSERT INTO user_preferences (preference_key, theme) 
-         VALUES ($1, $2) 
-         ON CONFLICT (preference_key) 
-         DO UPDATE SET theme = $2, updated_at = NOW()",
-        &[&preference_key, &theme],
-    ).await?;
-
-    Ok(())
+    Ok((
+        [(HX_TRIGGER, trigger.to_string())],
+        ThemeToggleTemplate { theme: ThemeView::new(name, follows_system) },
+    ).into_response())
 }
 
 // ensure user has a valid token, creating one if needed
-async fn ensure_user_token(
+pub async fn ensure_user_token(
     user_context: &UserContext,
     cookies: &Cookies,
 ) -> Result<token::Claims, Box<dyn std::error::Error + Send + Sync>> {
     match user_context {
         UserContext::Authenticated(claims) => {
             if token::should_refresh_token(claims) {
-                let new_token = if let Some(user_id) = claims.user_id {
-                    token::generate_user_token(user_id)?
-                } else {
-                    token::generate_anonymous_token()?
-                };
+                let new_token = token::reissue_token(claims, claims.theme.as_deref())?;
                 
                 set_auth_cookie(cookies, &new_token);
                 Ok(token::verify_token(&new_token)?)
@@ -146,7 +140,7 @@ async fn ensure_user_token(
     }
 }
 
-fn set_auth_cookie(cookies: &Cookies, token: &str) {
+pub fn set_auth_cookie(cookies: &Cookies, token: &str) {
     let cookie = Cookie::build(("auth_token", token.to_string()))
         .http_only(true)
         .secure(true)
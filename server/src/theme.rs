@@ -0,0 +1,151 @@
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::routes::icons;
+
+// embedded at compile time so the binary never disagrees with the css generated from the same file
+static REGISTRY: Lazy<ThemeRegistry> = Lazy::new(|| {
+    let registry: ThemeRegistry = toml::from_str(include_str!("../../themes.toml"))
+        .expect("themes.toml is not valid");
+    registry.validate().expect("themes.toml is not valid");
+    registry
+});
+
+pub const SYSTEM_THEME: &str = "system";
+pub const SYSTEM_LABEL: &str = "System";
+// shown on the toggle when following the os comes next
+const SYSTEM_ICON: &str = "monitor";
+
+// schemes the client hint and the os media query can report, both need a theme of the same name
+const SCHEME_THEMES: [&str; 2] = ["light", "dark"];
+
+#[derive(Debug, Deserialize)]
+pub struct Theme {
+    pub name: String,
+    pub label: String,
+    pub icon: String,
+    pub variables: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThemeRegistry {
+    default: String,
+    themes: Vec<Theme>,
+}
+
+impl ThemeRegistry {
+    fn validate(&self) -> Result<(), String> {
+        for (i, theme) in self.themes.iter().enumerate() {
+            let valid_name = !theme.name.is_empty()
+                && theme.name.len() <= 32
+                && theme.name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+            if !valid_name || theme.name == SYSTEM_THEME {
+                return Err(format!("invalid theme name '{}'", theme.name));
+            }
+            if self.themes[..i].iter().any(|other| other.name == theme.name) {
+                return Err(format!("theme '{}' is defined twice", theme.name));
+            }
+            if icons::get_svg_content(&theme.icon).is_none() {
+                return Err(format!("theme '{}' uses unknown icon '{}'", theme.name, theme.icon));
+            }
+        }
+
+        for name in SCHEME_THEMES.iter().chain(std::iter::once(&self.default.as_str())) {
+            if self.get(name).is_none() {
+                return Err(format!("theme '{}' must be defined", name));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn default_name(&self) -> &str {
+        &self.default
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Theme> {
+        self.themes.iter().find(|theme| theme.name == name)
+    }
+
+    pub fn themes(&self) -> &[Theme] {
+        &self.themes
+    }
+
+    // (name, icon) in toggle order, every theme and then following the os again
+    pub fn choices(&self) -> Vec<(&str, &str)> {
+        self.themes.iter()
+            .map(|theme| (theme.name.as_str(), theme.icon.as_str()))
+            .chain(std::iter::once((SYSTEM_THEME, SYSTEM_ICON)))
+            .collect()
+    }
+
+    // the choice the toggle switches to next, wrapping around
+    pub fn next_choice(&self, current: &str) -> (&str, &str) {
+        let choices = self.choices();
+        let position = choices.iter().position(|(name, _)| *name == current);
+        match position {
+            Some(i) => choices[(i + 1) % choices.len()],
+            None => choices[0],
+        }
+    }
+
+    pub fn is_valid_choice(&self, name: &str) -> bool {
+        name == SYSTEM_THEME || self.get(name).is_some()
+    }
+}
+
+pub fn registry() -> &'static ThemeRegistry {
+    &REGISTRY
+}
+
+pub fn default_theme() -> &'static str {
+    registry().default_name()
+}
+
+// everything base.html needs to render the theme and the toggle button
+pub struct ThemeView {
+    pub name: String,
+    pub follows_system: bool,
+    pub toggle_icon: String,
+    // "name:icon" pairs in toggle order including "system", read by the toggle script
+    pub cycle: String,
+    // a signed-in user's active palette, injected as a <style> block
+    pub custom_css: Option<String>,
+}
+
+impl ThemeView {
+    pub fn new(name: String, follows_system: bool) -> Self {
+        let registry = registry();
+        let current = if follows_system { SYSTEM_THEME } else { name.as_str() };
+        let (_, next_icon) = registry.next_choice(current);
+        let toggle_icon = icons::render_icon(next_icon, icons::DEFAULT_CLASSES)
+            .map(|svg| svg.into_owned())
+            .unwrap_or_default();
+        let cycle = registry.choices()
+            .iter()
+            .map(|(name, icon)| format!("{}:{}", name, icon))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        Self { name, follows_system, toggle_icon, cycle, custom_css: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_registry_is_valid() {
+        let registry = registry();
+
+        assert!(registry.get(default_theme()).is_some());
+        assert!(registry.is_valid_choice(SYSTEM_THEME));
+        assert!(!registry.is_valid_choice("neon"));
+        let themes = registry.themes();
+        assert_eq!(registry.next_choice(&themes[themes.len() - 1].name).0, SYSTEM_THEME);
+        assert_eq!(registry.next_choice(SYSTEM_THEME).0, themes[0].name);
+        assert!(registry.choices().iter().all(|(_, icon)| icons::get_svg_content(icon).is_some()));
+    }
+}
@@ -11,10 +11,18 @@ static SECRET_KEY: Lazy<String> = Lazy::new(|| {
     })
 });
 
+// also keys the csrf tokens, which are only as good as this secret
+pub fn secret_key() -> &'static [u8] {
+    SECRET_KEY.as_bytes()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub anonymous_id: Option<String>,
     pub user_id: Option<i32>,
+    // last chosen theme, lets pages render without a database lookup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
     pub exp: usize,
     pub iat: usize,
 }
@@ -54,6 +62,7 @@ pub fn generate_anonymous_token() -> Result<String, TokenError> {
     let claims = Claims {
         anonymous_id: Some(Uuid::new_v4().to_string()),
         user_id: None,
+        theme: None,
         exp,
         iat: now,
     };
@@ -65,6 +74,8 @@ pub fn generate_anonymous_token() -> Result<String, TokenError> {
     ).map_err(|_| TokenError::GenerationFailed)
 }
 
+// nothing signs users in yet, refreshes go through reissue_token
+#[allow(dead_code)]
 pub fn generate_user_token(user_id: i32) -> Result<String, TokenError> {
     let now = chrono::Utc::now().timestamp() as usize;
     let exp = now + (30 * 24 * 60 * 60);
@@ -72,6 +83,7 @@ pub fn generate_user_token(user_id: i32) -> Result<String, TokenError> {
     let claims = Claims {
         anonymous_id: None,
         user_id: Some(user_id),
+        theme: None,
         exp,
         iat: now,
     };
@@ -83,6 +95,26 @@ pub fn generate_user_token(user_id: i32) -> Result<String, TokenError> {
     ).map_err(|_| TokenError::GenerationFailed)
 }
 
+// issue a fresh token for the same identity, keeping the preference key stable
+pub fn reissue_token(claims: &Claims, theme: Option<&str>) -> Result<String, TokenError> {
+    let now = chrono::Utc::now().timestamp() as usize;
+    let lifetime = if claims.user_id.is_some() { 30 } else { 365 };
+
+    let claims = Claims {
+        anonymous_id: claims.anonymous_id.clone(),
+        user_id: claims.user_id,
+        theme: theme.map(str::to_string),
+        exp: now + (lifetime * 24 * 60 * 60),
+        iat: now,
+    };
+
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(SECRET_KEY.as_bytes()),
+    ).map_err(|_| TokenError::GenerationFailed)
+}
+
 /// Verify and decode any token (anonymous or authenticated)
 pub fn verify_token(token: &str) -> Result<Claims, TokenError> {
     let validation = Validation::new(Algorithm::HS256);
@@ -147,4 +179,18 @@ mod tests {
         let key = get_preference_key(&claims);
         assert_eq!(key, "user_123");
     }
+
+    #[test]
+    fn test_reissue_keeps_identity_and_sets_theme() {
+        let token = generate_anonymous_token().unwrap();
+        let claims = verify_token(&token).unwrap();
+        assert!(claims.theme.is_none());
+
+        let reissued = reissue_token(&claims, Some("light")).unwrap();
+        let reissued_claims = verify_token(&reissued).unwrap();
+
+        assert_eq!(reissued_claims.anonymous_id, claims.anonymous_id);
+        assert_eq!(reissued_claims.theme.as_deref(), Some("light"));
+        assert_eq!(get_preference_key(&reissued_claims), get_preference_key(&claims));
+    }
 } 
\ No newline at end of file
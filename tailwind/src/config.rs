@@ -0,0 +1,232 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+
+const USAGE: &str = "usage: cargo run -p tailwind -- [--watch | --check] [--fetch] [--root <dir>] [--config <file>] [--output <dir>]";
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub watch: bool,
+    // build into a temp dir and fail when the committed output differs, writes nothing
+    pub check: bool,
+    pub fetch: bool,
+    root: Option<PathBuf>,
+    config: Option<PathBuf>,
+    output: Option<PathBuf>,
+}
+
+impl Args {
+    pub fn parse() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut args = Args::default();
+        let mut raw = env::args().skip(1);
+        while let Some(arg) = raw.next() {
+            match arg.as_str() {
+                "--watch" => args.watch = true,
+                "--check" => args.check = true,
+                "--fetch" => args.fetch = true,
+                "--root" | "--config" | "--output" => {
+                    // paths given on the command line are relative to where it was typed
+                    let value = raw.next().ok_or_else(|| format!("{} needs a path\n{}", arg, USAGE))?;
+                    let value = Some(env::current_dir()?.join(value));
+                    match arg.as_str() {
+                        "--root" => args.root = value,
+                        "--config" => args.config = value,
+                        _ => args.output = value,
+                    }
+                }
+                _ => return Err(format!("unknown argument {}\n{}", arg, USAGE).into()),
+            }
+        }
+
+        if args.watch && args.check {
+            return Err(format!("--watch and --check can't be combined\n{}", USAGE).into());
+        }
+        Ok(args)
+    }
+}
+
+// the nearest directory upwards with a [workspace] Cargo.toml, so the build runs from anywhere in the repo
+fn workspace_root() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let current = env::current_dir()?.canonicalize()?;
+    for directory in current.ancestors() {
+        if fs::read_to_string(directory.join("Cargo.toml")).is_ok_and(|manifest| manifest.contains("[workspace]")) {
+            return Ok(directory.to_path_buf());
+        }
+    }
+
+    // started from outside the repo, use the one this binary was built in
+    Ok(Path::new(env!("CARGO_MANIFEST_DIR")).join("..").canonicalize()?)
+}
+
+// [paths] in assets.toml, relative to the workspace root
+#[derive(Deserialize)]
+#[serde(default)]
+struct PathConfig {
+    input: PathBuf,
+    themes: PathBuf,
+    // input.css imports it, keep the two next to each other
+    themes_css: PathBuf,
+    // the generated @font-face rules, imported by input.css like themes_css
+    fonts_css: PathBuf,
+    vendor: PathBuf,
+    tailwindcss: PathBuf,
+    templates: PathBuf,
+    // watched for class names besides the templates, the @source globs in input.css
+    sources: Vec<PathBuf>,
+    output: PathBuf,
+    cache: PathBuf,
+}
+
+impl Default for PathConfig {
+    fn default() -> Self {
+        PathConfig {
+            input: "input.css".into(),
+            themes: "themes.toml".into(),
+            themes_css: "themes.css".into(),
+            fonts_css: "fonts.css".into(),
+            vendor: "vendor.toml".into(),
+            tailwindcss: "tailwindcss.toml".into(),
+            templates: "server/templates".into(),
+            sources: vec!["server/src".into()],
+            output: "static".into(),
+            cache: "target".into(),
+        }
+    }
+}
+
+// every input and output of the build as an absolute path
+#[derive(Debug, Clone)]
+pub struct Paths {
+    pub input: PathBuf,
+    pub themes: PathBuf,
+    pub themes_css: PathBuf,
+    pub fonts_css: PathBuf,
+    pub vendor: PathBuf,
+    pub tailwindcss: PathBuf,
+    pub templates: PathBuf,
+    pub sources: Vec<PathBuf>,
+    pub assets: PathBuf,
+    pub output: PathBuf,
+    // downloads (the tailwindcss cli, vendored scripts) kept between builds
+    pub cache: PathBuf,
+    // tailwind's own output before it is fingerprinted into `output`
+    pub work: PathBuf,
+}
+
+impl Paths {
+    pub fn manifest(&self) -> PathBuf {
+        self.output.join("manifest.json")
+    }
+
+    // .static.staging next to static/, same filesystem so publishing is a rename
+    pub fn staging(&self) -> PathBuf {
+        let name = self.output.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        self.output.with_file_name(format!(".{}.staging", name))
+    }
+}
+
+// which source files are served under a content hash, see assets.toml
+#[derive(Deserialize)]
+pub struct AssetConfig {
+    source: PathBuf,
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    // hashed copies kept per asset, so pages from the previous deploy still load theirs
+    pub keep_generations: usize,
+    #[serde(default)]
+    paths: PathConfig,
+    #[serde(default)]
+    pub fonts: Vec<FontConfig>,
+}
+
+// a font below `source` served as a subset woff2 instead of being copied as it is
+#[derive(Deserialize)]
+pub struct FontConfig {
+    pub source: String,
+    pub family: String,
+}
+
+impl FontConfig {
+    // fira_code.ttf -> fira_code.woff2
+    pub fn logical(&self) -> String {
+        let stem = self.source.rsplit_once('.').map_or(self.source.as_str(), |(stem, _)| stem);
+        format!("{}.woff2", stem)
+    }
+}
+
+// `*` stays within a directory, `**/` spans them, like .gitignore
+const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl AssetConfig {
+    // assets.toml and the paths it names, resolved against the workspace root
+    pub fn load(args: &Args) -> Result<(Self, Paths), Box<dyn std::error::Error>> {
+        let root = match &args.root {
+            Some(root) => root.canonicalize().map_err(|e| format!("--root {}: {}", root.display(), e))?,
+            None => workspace_root()?,
+        };
+        let file = args.config.clone().unwrap_or_else(|| root.join("assets.toml"));
+        let config: AssetConfig = toml::from_str(&fs::read_to_string(&file).map_err(|e| format!("{}: {}", file.display(), e))?)?;
+        if config.keep_generations == 0 {
+            return Err(format!("keep_generations in {} must be at least 1", file.display()).into());
+        }
+
+        let resolved = &config.paths;
+        let cache = root.join(&resolved.cache);
+        let paths = Paths {
+            input: root.join(&resolved.input),
+            themes: root.join(&resolved.themes),
+            themes_css: root.join(&resolved.themes_css),
+            fonts_css: root.join(&resolved.fonts_css),
+            vendor: root.join(&resolved.vendor),
+            tailwindcss: root.join(&resolved.tailwindcss),
+            templates: root.join(&resolved.templates),
+            sources: resolved.sources.iter().map(|source| root.join(source)).collect(),
+            assets: root.join(&config.source),
+            output: args.output.clone().unwrap_or_else(|| root.join(&resolved.output)),
+            work: cache.join("tailwind"),
+            cache,
+        };
+
+        Ok((config, paths))
+    }
+
+    // paths relative to the source directory with `/` separators, these are the logical names.
+    // fonts aren't among them, subset_fonts serves those
+    pub fn source_assets(&self, source: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let include = self.include.iter().map(|glob| Pattern::new(glob)).collect::<Result<Vec<_>, _>>()?;
+        let exclude = self.exclude.iter().map(|glob| Pattern::new(glob)).collect::<Result<Vec<_>, _>>()?;
+        let matches = |patterns: &[Pattern], name: &str| patterns.iter().any(|pattern| pattern.matches_with(name, GLOB_OPTIONS));
+
+        let mut assets = Vec::new();
+        let mut directories = vec![source.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            for entry in fs::read_dir(&directory)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+
+                let logical = path.strip_prefix(source)?
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let font = self.fonts.iter().any(|font| font.source == logical);
+                if matches(&include, &logical) && !matches(&exclude, &logical) && !font {
+                    assets.push(logical);
+                }
+            }
+        }
+
+        assets.sort();
+        Ok(assets)
+    }
+}
@@ -0,0 +1,549 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write as _;
+
+// table tag -> table data, sorted by tag as the sfnt table directory wants them
+type Tables = BTreeMap<[u8; 4], Vec<u8>>;
+
+const TRUETYPE: u32 = 0x0001_0000;
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Box<dyn std::error::Error>> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| "font data is truncated".into())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Box<dyn std::error::Error>> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| "font data is truncated".into())
+}
+
+fn slice(data: &[u8], start: usize, end: usize) -> Result<&[u8], Box<dyn std::error::Error>> {
+    data.get(start..end).ok_or_else(|| "font data is truncated".into())
+}
+
+fn parse_tables(font: &[u8]) -> Result<Tables, Box<dyn std::error::Error>> {
+    if read_u32(font, 0)? != TRUETYPE {
+        return Err("only TrueType (glyf) fonts can be subset".into());
+    }
+
+    let mut tables = Tables::new();
+    for record in 0..read_u16(font, 4)? as usize {
+        let record = 12 + record * 16;
+        let tag = slice(font, record, record + 4)?.try_into()?;
+        let offset = read_u32(font, record + 8)? as usize;
+        let length = read_u32(font, record + 12)? as usize;
+        tables.insert(tag, slice(font, offset, offset + length)?.to_vec());
+    }
+
+    Ok(tables)
+}
+
+fn table<'a>(tables: &'a Tables, tag: &[u8; 4]) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+    tables.get(tag)
+        .map(Vec::as_slice)
+        .ok_or_else(|| format!("font has no {} table", String::from_utf8_lossy(tag)).into())
+}
+
+// codepoint -> glyph from the unicode subtable, format 12 when the font has one, else format 4
+fn read_cmap(cmap: &[u8]) -> Result<BTreeMap<u32, u16>, Box<dyn std::error::Error>> {
+    let mut subtables = BTreeMap::new();
+    for record in 0..read_u16(cmap, 2)? as usize {
+        let record = 4 + record * 8;
+        let offset = read_u32(cmap, record + 4)? as usize;
+        subtables.insert(read_u16(cmap, offset)?, offset);
+    }
+
+    let mut characters = BTreeMap::new();
+    if let Some(&offset) = subtables.get(&12) {
+        for group in 0..read_u32(cmap, offset + 12)? as usize {
+            let group = offset + 16 + group * 12;
+            let (start, end, glyph) = (read_u32(cmap, group)?, read_u32(cmap, group + 4)?, read_u32(cmap, group + 8)?);
+            for codepoint in start..=end {
+                characters.insert(codepoint, (glyph + codepoint - start) as u16);
+            }
+        }
+    } else if let Some(&offset) = subtables.get(&4) {
+        let segments = read_u16(cmap, offset + 6)? as usize / 2;
+        let ends = offset + 14;
+        let starts = ends + segments * 2 + 2;
+        let deltas = starts + segments * 2;
+        let range_offsets = deltas + segments * 2;
+        for segment in 0..segments {
+            let (start, end) = (read_u16(cmap, starts + segment * 2)?, read_u16(cmap, ends + segment * 2)?);
+            let delta = read_u16(cmap, deltas + segment * 2)?;
+            let range_offset = read_u16(cmap, range_offsets + segment * 2)? as usize;
+            for codepoint in start..=end.min(0xFFFE) {
+                let glyph = if range_offset == 0 {
+                    codepoint.wrapping_add(delta)
+                } else {
+                    // idRangeOffset counts from its own position into glyphIdArray
+                    let index = range_offsets + segment * 2 + range_offset + (codepoint - start) as usize * 2;
+                    match read_u16(cmap, index)? {
+                        0 => 0,
+                        glyph => glyph.wrapping_add(delta),
+                    }
+                };
+                if glyph != 0 {
+                    characters.insert(codepoint as u32, glyph);
+                }
+            }
+        }
+    } else {
+        return Err("font has no unicode cmap subtable".into());
+    }
+
+    Ok(characters)
+}
+
+// a (0,3) and a (3,1) record sharing one format 4 subtable, enough for the basic multilingual plane
+fn write_cmap(characters: &BTreeMap<u32, u16>) -> Vec<u8> {
+    // runs where codepoint and glyph both count up by one share a segment and a delta
+    let mut segments: Vec<(u16, u16, u16)> = Vec::new();
+    for (&codepoint, &glyph) in characters.range(..0xFFFF) {
+        let codepoint = codepoint as u16;
+        match segments.last_mut() {
+            Some((start, end, first_glyph)) if *end + 1 == codepoint && first_glyph.wrapping_add(codepoint - *start) == glyph => {
+                *end = codepoint;
+            }
+            _ => segments.push((codepoint, codepoint, glyph)),
+        }
+    }
+    // the required final segment, maps 0xFFFF to .notdef
+    segments.push((0xFFFF, 0xFFFF, 0));
+
+    let count = segments.len() as u16;
+    let selector = 15 - count.leading_zeros() as u16;
+    let search_range = 2 << selector;
+
+    let mut subtable = Vec::new();
+    for field in [4, 16 + 8 * count, 0, count * 2, search_range, selector, count * 2 - search_range] {
+        subtable.extend(field.to_be_bytes());
+    }
+    subtable.extend(segments.iter().flat_map(|(_, end, _)| end.to_be_bytes()));
+    subtable.extend([0, 0]);
+    subtable.extend(segments.iter().flat_map(|(start, _, _)| start.to_be_bytes()));
+    subtable.extend(segments.iter().flat_map(|(start, _, glyph)| glyph.wrapping_sub(*start).to_be_bytes()));
+    subtable.extend(segments.iter().flat_map(|_| [0, 0]));
+
+    let mut cmap = Vec::new();
+    for field in [0u16, 2, 0, 3] {
+        cmap.extend(field.to_be_bytes());
+    }
+    cmap.extend(20u32.to_be_bytes());
+    for field in [3u16, 1] {
+        cmap.extend(field.to_be_bytes());
+    }
+    cmap.extend(20u32.to_be_bytes());
+    cmap.extend(subtable);
+    cmap
+}
+
+// glyphs a composite glyph is assembled from
+fn components(glyph: &[u8]) -> Result<Vec<u16>, Box<dyn std::error::Error>> {
+    const ARG_1_AND_2_ARE_WORDS: u16 = 0x0001;
+    const WE_HAVE_A_SCALE: u16 = 0x0008;
+    const MORE_COMPONENTS: u16 = 0x0020;
+    const WE_HAVE_AN_X_AND_Y_SCALE: u16 = 0x0040;
+    const WE_HAVE_A_TWO_BY_TWO: u16 = 0x0080;
+
+    if glyph.is_empty() || (read_u16(glyph, 0)? as i16) >= 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut components = Vec::new();
+    let mut offset = 10;
+    loop {
+        let flags = read_u16(glyph, offset)?;
+        components.push(read_u16(glyph, offset + 2)?);
+        offset += 4 + if flags & ARG_1_AND_2_ARE_WORDS != 0 { 4 } else { 2 };
+        offset += if flags & WE_HAVE_A_SCALE != 0 {
+            2
+        } else if flags & WE_HAVE_AN_X_AND_Y_SCALE != 0 {
+            4
+        } else if flags & WE_HAVE_A_TWO_BY_TWO != 0 {
+            8
+        } else {
+            0
+        };
+        if flags & MORE_COMPONENTS == 0 {
+            return Ok(components);
+        }
+    }
+}
+
+// per-glyph variation data of the glyphs in `keep`, the rest become empty like their outlines
+fn subset_gvar(gvar: &[u8], keep: &BTreeSet<u16>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let axis_count = read_u16(gvar, 4)? as usize;
+    let shared_count = read_u16(gvar, 6)? as usize;
+    let shared_offset = read_u32(gvar, 8)? as usize;
+    let glyph_count = read_u16(gvar, 12)? as usize;
+    let flags = read_u16(gvar, 14)?;
+    let data_offset = read_u32(gvar, 16)? as usize;
+
+    let mut offsets = Vec::with_capacity(glyph_count + 1);
+    for glyph in 0..=glyph_count {
+        offsets.push(match flags & 1 {
+            0 => read_u16(gvar, 20 + glyph * 2)? as usize * 2,
+            _ => read_u32(gvar, 20 + glyph * 4)? as usize,
+        });
+    }
+
+    // long offsets from here on, so nothing needs padding
+    let shared = slice(gvar, shared_offset, shared_offset + shared_count * axis_count * 2)?;
+    let new_shared_offset = 20 + (glyph_count + 1) * 4;
+    let new_data_offset = new_shared_offset + shared.len();
+
+    let mut data: Vec<u8> = Vec::new();
+    let mut new_offsets = vec![0u32];
+    for glyph in 0..glyph_count {
+        if keep.contains(&(glyph as u16)) {
+            data.extend(slice(gvar, data_offset + offsets[glyph], data_offset + offsets[glyph + 1])?);
+        }
+        new_offsets.push(data.len() as u32);
+    }
+
+    let mut subset = gvar[..12].to_vec();
+    subset[8..12].copy_from_slice(&(new_shared_offset as u32).to_be_bytes());
+    subset.extend((glyph_count as u16).to_be_bytes());
+    subset.extend((flags | 1).to_be_bytes());
+    subset.extend((new_data_offset as u32).to_be_bytes());
+    subset.extend(new_offsets.iter().flat_map(|offset| offset.to_be_bytes()));
+    subset.extend(shared);
+    subset.extend(data);
+    Ok(subset)
+}
+
+// drops the outlines of every character not in `characters`. glyph ids stay as they are, so GSUB
+// (the ligatures), GPOS, hmtx and HVAR are kept untouched; glyphs cmap doesn't reach are kept
+// whole since only GSUB can tell whether a kept character still needs them
+pub fn subset(font: &[u8], characters: &BTreeSet<char>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut tables = parse_tables(font)?;
+    let head = table(&tables, b"head")?;
+    let glyph_count = read_u16(table(&tables, b"maxp")?, 4)? as usize;
+    let long_loca = read_u16(head, 50)? == 1;
+
+    let loca = table(&tables, b"loca")?;
+    let mut locations = Vec::with_capacity(glyph_count + 1);
+    for glyph in 0..=glyph_count {
+        locations.push(match long_loca {
+            true => read_u32(loca, glyph * 4)? as usize,
+            false => read_u16(loca, glyph * 2)? as usize * 2,
+        });
+    }
+    let glyf = table(&tables, b"glyf")?;
+    let outline = |glyph: u16| slice(glyf, locations[glyph as usize], locations[glyph as usize + 1]);
+
+    let cmap = read_cmap(table(&tables, b"cmap")?)?;
+    let encoded: BTreeSet<u16> = cmap.values().copied().collect();
+    let kept_cmap: BTreeMap<u32, u16> = cmap.into_iter()
+        .filter(|(codepoint, _)| char::from_u32(*codepoint).is_some_and(|character| characters.contains(&character)))
+        .collect();
+
+    let mut keep: BTreeSet<u16> = (0..glyph_count as u16).filter(|glyph| !encoded.contains(glyph)).collect();
+    keep.insert(0);
+    keep.extend(kept_cmap.values());
+    let mut pending: Vec<u16> = keep.iter().copied().collect();
+    while let Some(glyph) = pending.pop() {
+        for component in components(outline(glyph)?)? {
+            if (component as usize) < glyph_count && keep.insert(component) {
+                pending.push(component);
+            }
+        }
+    }
+
+    let mut new_glyf = Vec::new();
+    let mut new_loca = vec![0u32];
+    for glyph in 0..glyph_count as u16 {
+        if keep.contains(&glyph) {
+            new_glyf.extend(outline(glyph)?);
+            // glyphs start on even offsets
+            new_glyf.resize(new_glyf.len().next_multiple_of(2), 0);
+        }
+        new_loca.push(new_glyf.len() as u32);
+    }
+
+    let mut head = head.to_vec();
+    head[50..52].copy_from_slice(&1u16.to_be_bytes());
+    head[8..12].fill(0);
+    if let Some(gvar) = tables.get(b"gvar") {
+        let gvar = subset_gvar(gvar, &keep)?;
+        tables.insert(*b"gvar", gvar);
+    }
+    // glyph names are only for font editors, version 3 has none
+    if let Some(post) = tables.get_mut(b"post") {
+        post.truncate(32);
+        post[..4].copy_from_slice(&0x0003_0000u32.to_be_bytes());
+    }
+    // the signature covered the original file
+    tables.remove(b"DSIG");
+
+    tables.insert(*b"cmap", write_cmap(&kept_cmap));
+    tables.insert(*b"glyf", new_glyf);
+    tables.insert(*b"loca", new_loca.iter().flat_map(|offset| offset.to_be_bytes()).collect());
+    tables.insert(*b"head", head);
+
+    let adjustment = 0xB1B0_AFBAu32.wrapping_sub(checksum(&sfnt(&tables)));
+    if let Some(head) = tables.get_mut(b"head") {
+        head[8..12].copy_from_slice(&adjustment.to_be_bytes());
+    }
+
+    Ok(sfnt(&tables))
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+// head is summed with checkSumAdjustment zeroed, the adjustment balances the whole font instead
+fn table_checksum(tag: &[u8; 4], data: &[u8]) -> u32 {
+    let sum = checksum(data);
+    match (tag, read_u32(data, 8)) {
+        (b"head", Ok(adjustment)) => sum.wrapping_sub(adjustment),
+        _ => sum,
+    }
+}
+
+fn padded(length: usize) -> usize {
+    length.next_multiple_of(4)
+}
+
+fn sfnt(tables: &Tables) -> Vec<u8> {
+    let count = tables.len() as u16;
+    let selector = 15 - count.leading_zeros() as u16;
+    let search_range = 16 << selector;
+
+    let mut font = TRUETYPE.to_be_bytes().to_vec();
+    for field in [count, search_range, selector, count * 16 - search_range] {
+        font.extend(field.to_be_bytes());
+    }
+
+    let mut offset = 12 + tables.len() * 16;
+    for (tag, data) in tables {
+        font.extend(tag);
+        font.extend(table_checksum(tag, data).to_be_bytes());
+        font.extend((offset as u32).to_be_bytes());
+        font.extend((data.len() as u32).to_be_bytes());
+        offset += padded(data.len());
+    }
+    for data in tables.values() {
+        font.extend(data);
+        font.resize(padded(font.len()), 0);
+    }
+
+    font
+}
+
+// tables the woff2 table directory names by index instead of spelling out the tag
+const KNOWN_TAGS: [&[u8; 4]; 63] = [
+    b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post", b"cvt ", b"fpgm", b"glyf", b"loca", b"prep",
+    b"CFF ", b"VORG", b"EBDT", b"EBLC", b"gasp", b"hdmx", b"kern", b"LTSH", b"PCLT", b"VDMX", b"vhea", b"vmtx", b"BASE",
+    b"GDEF", b"GPOS", b"GSUB", b"EBSC", b"JSTF", b"MATH", b"CBDT", b"CBLC", b"COLR", b"CPAL", b"SVG ", b"sbix", b"acnt",
+    b"avar", b"bdat", b"bloc", b"bsln", b"cvar", b"fdsc", b"feat", b"fmtx", b"fvar", b"gvar", b"hsty", b"just", b"lcar",
+    b"mort", b"morx", b"opbd", b"prop", b"trak", b"Zapf", b"Silf", b"Glat", b"Gloc", b"Feat", b"Sill",
+];
+
+fn base128(mut value: u32, out: &mut Vec<u8>) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+// the font as woff2, every table brotli compressed in one stream. glyf and loca use the null
+// transform, the glyf transform would save a little more but the whole font is small by now
+pub fn woff2(font: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    const NULL_TRANSFORM: u8 = 3 << 6;
+
+    let tables = parse_tables(font)?;
+    // the spec wants loca right behind glyf in the directory
+    let mut order: Vec<&[u8; 4]> = tables.keys().filter(|tag| *tag != b"loca").collect();
+    if let Some(glyf) = order.iter().position(|tag| *tag == b"glyf") {
+        order.insert(glyf + 1, b"loca");
+    }
+
+    let mut directory = Vec::new();
+    let mut data = Vec::new();
+    for tag in order {
+        let known = KNOWN_TAGS.iter().position(|known| *known == tag);
+        let transform = if tag == b"glyf" || tag == b"loca" { NULL_TRANSFORM } else { 0 };
+        directory.push(known.unwrap_or(63) as u8 | transform);
+        if known.is_none() {
+            directory.extend(tag);
+        }
+        base128(tables[tag].len() as u32, &mut directory);
+        data.extend(&tables[tag]);
+    }
+
+    let mut compressed = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::with_params(&mut compressed, 4096, &brotli::enc::BrotliEncoderParams {
+            quality: 11,
+            lgwin: 22,
+            mode: brotli::enc::backward_references::BrotliEncoderMode::BROTLI_MODE_FONT,
+            ..Default::default()
+        });
+        writer.write_all(&data)?;
+    }
+
+    let sfnt_size = 12 + tables.len() * 16 + tables.values().map(|data| padded(data.len())).sum::<usize>();
+    let length = padded(48 + directory.len() + compressed.len());
+    let mut woff2 = b"wOF2".to_vec();
+    woff2.extend(TRUETYPE.to_be_bytes());
+    woff2.extend((length as u32).to_be_bytes());
+    woff2.extend((tables.len() as u16).to_be_bytes());
+    woff2.extend([0, 0]);
+    woff2.extend((sfnt_size as u32).to_be_bytes());
+    woff2.extend((compressed.len() as u32).to_be_bytes());
+    // font version 1.0, then no metadata and no private data
+    woff2.extend([0, 1, 0, 0]);
+    woff2.extend([0; 20]);
+    woff2.extend(directory);
+    woff2.extend(compressed);
+    woff2.resize(length, 0);
+    Ok(woff2)
+}
+
+// "300 700" for a variable font with a weight axis, what @font-face needs so the browser
+// doesn't synthesize bold on top of it
+pub fn weight_range(font: &[u8]) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let tables = parse_tables(font)?;
+    let Some(fvar) = tables.get(b"fvar") else {
+        return Ok(None);
+    };
+
+    let axes = read_u16(fvar, 4)? as usize;
+    let axis_size = read_u16(fvar, 10)? as usize;
+    for axis in 0..read_u16(fvar, 8)? as usize {
+        let axis = axes + axis * axis_size;
+        if slice(fvar, axis, axis + 4)? == b"wght" {
+            // 16.16 fixed point
+            let min = read_u32(fvar, axis + 4)? as i32 >> 16;
+            let max = read_u32(fvar, axis + 12)? as i32 >> 16;
+            return Ok(Some(format!("{} {}", min, max)));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_base128() {
+        let encode = |value| {
+            let mut out = Vec::new();
+            base128(value, &mut out);
+            out
+        };
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(127), [0x7F]);
+        assert_eq!(encode(128), [0x81, 0x00]);
+        assert_eq!(encode(16384), [0x81, 0x80, 0x00]);
+    }
+
+    // reads back what woff2() writes: one brotli stream, glyf and loca untransformed
+    fn decode_woff2(woff2: &[u8]) -> Tables {
+        assert_eq!(&woff2[..4], b"wOF2");
+        assert_eq!(read_u32(woff2, 4).unwrap(), TRUETYPE);
+        assert_eq!(read_u32(woff2, 8).unwrap() as usize, woff2.len());
+        let count = read_u16(woff2, 12).unwrap() as usize;
+        let sfnt_size = read_u32(woff2, 16).unwrap() as usize;
+        let compressed_size = read_u32(woff2, 20).unwrap() as usize;
+
+        let mut offset = 48;
+        let mut directory = Vec::new();
+        for _ in 0..count {
+            let flags = woff2[offset];
+            offset += 1;
+            let tag: [u8; 4] = match (flags & 63) as usize {
+                63 => {
+                    offset += 4;
+                    woff2[offset - 4..offset].try_into().unwrap()
+                }
+                known => *KNOWN_TAGS[known],
+            };
+            // transform version 3 means untransformed for glyf and loca, 0 for everything else
+            let untransformed = if tag == *b"glyf" || tag == *b"loca" { 3 } else { 0 };
+            assert_eq!(flags >> 6, untransformed, "{} is transformed", String::from_utf8_lossy(&tag));
+
+            let mut length = 0;
+            loop {
+                let byte = woff2[offset];
+                offset += 1;
+                length = length << 7 | usize::from(byte & 0x7F);
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            directory.push((tag, length));
+        }
+
+        let glyf = directory.iter().position(|(tag, _)| tag == b"glyf").unwrap();
+        assert_eq!(&directory[glyf + 1].0, b"loca");
+
+        let mut data = Vec::new();
+        brotli::Decompressor::new(&woff2[offset..offset + compressed_size], 4096).read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), directory.iter().map(|(_, length)| length).sum::<usize>());
+
+        let mut tables = Tables::new();
+        let mut start = 0;
+        for (tag, length) in directory {
+            tables.insert(tag, data[start..start + length].to_vec());
+            start += length;
+        }
+        assert_eq!(sfnt_size, 12 + count * 16 + tables.values().map(|data| padded(data.len())).sum::<usize>());
+        tables
+    }
+
+    #[test]
+    fn test_woff2_round_trip() {
+        let original = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../assets/fira_code.ttf")).unwrap();
+        let characters: BTreeSet<char> = "Hello, world! -> != ═".chars().collect();
+        let subset = subset(&original, &characters).unwrap();
+
+        let decoded = decode_woff2(&woff2(&subset).unwrap());
+        assert_eq!(decoded, parse_tables(&subset).unwrap());
+
+        // the font a browser rebuilds from it: every table matches its checksum and
+        // head's checkSumAdjustment balances the whole file
+        let font = sfnt(&decoded);
+        for record in 0..decoded.len() {
+            let record = 12 + record * 16;
+            let tag: [u8; 4] = font[record..record + 4].try_into().unwrap();
+            let offset = read_u32(&font, record + 8).unwrap() as usize;
+            let length = read_u32(&font, record + 12).unwrap() as usize;
+            assert_eq!(read_u32(&font, record + 4).unwrap(), table_checksum(&tag, &font[offset..offset + length]));
+        }
+        assert_eq!(checksum(&font), 0xB1B0_AFBA);
+
+        // glyph ids are kept, kept characters map to their outlines and the rest are gone
+        let original = ttf_parser::Face::parse(&original, 0).unwrap();
+        let face = ttf_parser::Face::parse(&font, 0).unwrap();
+        assert_eq!(face.number_of_glyphs(), original.number_of_glyphs());
+        for character in characters.iter().filter(|character| !character.is_whitespace()) {
+            let glyph = face.glyph_index(*character);
+            assert_eq!(glyph, original.glyph_index(*character), "{:?}", character);
+            assert!(face.glyph_bounding_box(glyph.unwrap()).is_some(), "{:?} has no outline", character);
+        }
+        let dropped = original.glyph_index('é').unwrap();
+        assert_eq!(face.glyph_index('é'), None);
+        assert!(face.glyph_bounding_box(dropped).is_none());
+    }
+
+    #[test]
+    fn test_cmap_round_trip() {
+        let characters = BTreeMap::from([(0x20, 3), (0x21, 4), (0x22, 5), (0x41, 40), (0x2550, 900), (0x2551, 950)]);
+        assert_eq!(read_cmap(&write_cmap(&characters)).unwrap(), characters);
+    }
+}
@@ -1,76 +1,629 @@
-use std::process::Command;
+use std::process::{Command, ExitCode};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fmt::Write;
+use std::io::Write as _;
 use std::fs;
-use sha2::{Sha256, Digest};
-use regex::Regex;
-use glob::glob;
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("running tailwind css...");
-    let output = Command::new("tailwindcss")
-        .args(["-i", "input.css", "-o", "static/computed.css", "--minify"])
-        .output()?;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use base64::Engine;
+use flate2::{write::GzEncoder, Compression};
+use sha2::{Sha256, Sha384, Digest};
+use glob::{glob, Pattern};
+use serde::{Deserialize, Serialize};
+
+mod config;
+mod fonts;
+mod tailwindcss;
+mod watch;
+
+use config::{Args, AssetConfig, Paths};
+use tailwindcss::TailwindCli;
+
+// only the parts of themes.toml needed for css, the server reads the rest
+#[derive(Deserialize)]
+struct ThemeRegistry {
+    default: String,
+    themes: Vec<ThemeDefinition>,
+}
+
+#[derive(Deserialize)]
+struct ThemeDefinition {
+    name: String,
+    variables: BTreeMap<String, String>,
+}
+
+fn generate_theme_css(paths: &Paths, write: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    let registry: ThemeRegistry = toml::from_str(&fs::read_to_string(&paths.themes)?)?;
+    let default = registry.themes.iter()
+        .find(|theme| theme.name == registry.default)
+        .ok_or_else(|| format!("default theme '{}' is not defined in themes.toml", registry.default))?;
+
+    let mut css = String::from("/* generated from themes.toml by `cargo run -p tailwind`, do not edit */\n");
+
+    // the default theme also applies to :root so pages render even without a theme class.
+    // it comes first so the class selectors below override it
+    let blocks = std::iter::once((":root".to_string(), default))
+        .chain(registry.themes.iter().map(|theme| (format!(".{}", theme.name), theme)));
+    for (selector, theme) in blocks {
+        writeln!(css, "\n{} {{", selector)?;
+        for (name, value) in &theme.variables {
+            writeln!(css, "  --{}: {};", name, value)?;
+        }
+        writeln!(css, "}}")?;
+    }
+
+    write_generated(&paths.themes_css, &css, write)
+}
+
+// @font-face rules for the fonts in assets.toml. they name the logical woff2, fingerprint_css
+// points the url at the hashed copy
+fn generate_font_css(paths: &Paths, assets: &AssetConfig, write: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut css = String::from("/* generated from assets.toml by `cargo run -p tailwind`, do not edit */\n");
+    for font in &assets.fonts {
+        let weight = fonts::weight_range(&fs::read(paths.assets.join(&font.source))?)?;
+        writeln!(css, "\n@font-face {{")?;
+        writeln!(css, "  font-family: '{}';", font.family)?;
+        writeln!(css, "  src: url('/static/{}') format('woff2');", font.logical())?;
+        writeln!(css, "  font-weight: {};", weight.as_deref().unwrap_or("normal"))?;
+        writeln!(css, "  font-style: normal;")?;
+        writeln!(css, "  font-display: swap;")?;
+        writeln!(css, "}}")?;
+    }
+
+    write_generated(&paths.fonts_css, &css, write)
+}
+
+// returns false when the file on disk is stale, only writes it when `write` is set
+fn write_generated(path: &Path, css: &str, write: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    let previous = fs::read_to_string(path).unwrap_or_default();
+    if previous == css {
+        println!("  {} is up to date", path.display());
+        return Ok(true);
+    }
+    if write {
+        write_atomic(path, css.as_bytes())?;
+        println!("  updated: {}", path.display());
+    }
+
+    Ok(write)
+}
+
+// third-party scripts from vendor.toml, served from static/ instead of a cdn
+#[derive(Deserialize)]
+struct VendorManifest {
+    scripts: Vec<VendorScript>,
+}
 
+#[derive(Deserialize)]
+struct VendorScript {
+    name: String,
+    url: String,
+    // sha384-... as in an integrity attribute, required. downloads that don't match are rejected
+    integrity: Option<String>,
+}
+
+fn subresource_integrity(content: &[u8]) -> String {
+    format!("sha384-{}", base64::engine::general_purpose::STANDARD.encode(Sha384::digest(content)))
+}
+
+// downloads are cached per url, so only a version bump in vendor.toml needs the network
+fn cached_script(paths: &Paths, script: &VendorScript) -> PathBuf {
+    let url_hash = format!("{:x}", Sha256::digest(script.url.as_bytes()));
+    paths.cache.join("vendor").join(format!("{}-{}.js", script.name, &url_hash[..16]))
+}
+
+fn fetch_script(paths: &Paths, script: &VendorScript) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let cached = cached_script(paths, script);
+    if let Ok(content) = fs::read(&cached) {
+        return Ok(content);
+    }
+
+    let content = download(&script.url)?;
+    fs::create_dir_all(paths.cache.join("vendor"))?;
+    fs::write(&cached, &content)?;
+    Ok(content)
+}
+
+fn download(url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    println!("  downloading {}", url);
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", url])
+        .output()?;
     if !output.status.success() {
+        return Err(format!("downloading {} failed: {}", url, String::from_utf8_lossy(&output.stderr)).into());
+    }
+
+    Ok(output.stdout)
+}
+
+// where each logical asset name is served from, read by the server's asset() template helper
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ManifestEntry {
+    file: String,
+    integrity: String,
+}
+
+type AssetManifest = BTreeMap<String, ManifestEntry>;
+
+// the server's ServeDir picks these up by suffix when the browser accepts the encoding
+const PRECOMPRESSED: [&str; 2] = ["br", "gz"];
+
+// fonts like woff2 and images are compressed already
+const COMPRESSIBLE: [&str; 6] = ["css", "js", "svg", "ttf", "json", "txt"];
+
+fn sibling(path: &Path, encoding: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(encoding);
+    PathBuf::from(name)
+}
+
+// stages .br and .gz copies of a hashed file once, skipped when they don't save anything
+fn precompress(paths: &Paths, file: &str, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    if !Path::new(file).extension().and_then(OsStr::to_str).is_some_and(|extension| COMPRESSIBLE.contains(&extension)) {
+        return Ok(());
+    }
+
+    for encoding in PRECOMPRESSED {
+        if sibling(&paths.output.join(file), encoding).exists() {
+            continue;
+        }
+
+        let compressed = match encoding {
+            "br" => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+                writer.write_all(content)?;
+                writer.into_inner()
+            }
+            _ => {
+                let mut writer = GzEncoder::new(Vec::new(), Compression::best());
+                writer.write_all(content)?;
+                writer.finish()?
+            }
+        };
+
+        if compressed.len() < content.len() {
+            stage(paths, &sibling(Path::new(file), encoding), &compressed)?;
+        }
+    }
+
+    Ok(())
+}
+
+// {dir}/{stem}.{hash}.{ext}, the server treats this shape as immutable
+fn hashed_name(logical: &str, content: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let (stem, extension) = split_extension(logical)?;
+    let hash = format!("{:x}", Sha256::digest(content));
+    Ok(format!("{}.{}.{}", stem, &hash[..8], extension))
+}
+
+fn split_extension(logical: &str) -> Result<(&str, &str), Box<dyn std::error::Error>> {
+    logical.rsplit_once('.')
+        .filter(|(stem, _)| !stem.ends_with('/') && !stem.is_empty())
+        .ok_or_else(|| format!("{} has no file extension", logical).into())
+}
+
+// the file as it will appear in the output, renamed into place by publish()
+fn stage(paths: &Paths, file: &Path, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let staged = paths.staging().join(file);
+    if let Some(parent) = staged.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&staged, content)?;
+    Ok(())
+}
+
+// {dir}/{stem}.{hash}.{ext} for the manifest, staged unless the output already has it
+fn fingerprint(paths: &Paths, logical: &str, content: &[u8]) -> Result<ManifestEntry, Box<dyn std::error::Error>> {
+    let file = hashed_name(logical, content)?;
+
+    let published = paths.output.join(&file);
+    if published.exists() {
+        // rebuilding an older version makes it the newest generation again
+        fs::File::options().write(true).open(&published)?.set_modified(SystemTime::now())?;
+    } else {
+        stage(paths, Path::new(&file), content)?;
+    }
+    precompress(paths, &file, content)?;
+
+    Ok(ManifestEntry { integrity: subresource_integrity(content), file })
+}
+
+// an interrupted build leaves its staged files behind, they were never referenced
+fn clear_staging(paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    match fs::remove_dir_all(paths.staging()) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// renames every staged file into the output. they are complete before they appear under their
+// final name, and the staging directory sits next to the output so the renames stay on one filesystem
+fn publish(paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    let staging = paths.staging();
+    if !staging.exists() {
+        return Ok(());
+    }
+
+    let mut directories = vec![staging.clone()];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                directories.push(path);
+                continue;
+            }
+
+            let published = paths.output.join(path.strip_prefix(&staging)?);
+            if let Some(parent) = published.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&path, &published)?;
+            println!("  wrote: {}", published.display());
+        }
+    }
+
+    clear_staging(paths)
+}
+
+// "fonts/fira_code.74283f81.woff2" -> "fonts/fira_code.woff2", None for files the build didn't hash
+fn logical_name(file: &str) -> Option<String> {
+    let (rest, extension) = file.rsplit_once('.')?;
+    let (stem, hash) = rest.rsplit_once('.')?;
+    let hashed = hash.len() == 8 && hash.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'));
+    (hashed && !stem.is_empty() && !stem.ends_with('/')).then(|| format!("{}.{}", stem, extension))
+}
+
+// removes hashed copies older than the kept generations, only once the manifest no longer points at them.
+// names dropped from the manifest count too, their last generations stay for pages still cached
+fn collect_garbage(paths: &Paths, manifest: &AssetManifest, keep_generations: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let mut previous: BTreeMap<String, Vec<(SystemTime, PathBuf)>> = BTreeMap::new();
+    for entry in glob(&format!("{}/**/*", Pattern::escape(&paths.output.to_string_lossy())))? {
+        let path = entry?;
+        if !path.is_file() {
+            continue;
+        }
+
+        let file = path.strip_prefix(&paths.output)?.to_string_lossy().replace('\\', "/");
+        let Some(logical) = logical_name(&file) else {
+            continue;
+        };
+        if manifest.get(&logical).is_some_and(|entry| entry.file == file) {
+            continue;
+        }
+        previous.entry(logical).or_default().push((fs::metadata(&path)?.modified()?, path));
+    }
+
+    for (_, mut generations) in previous {
+        generations.sort_by_key(|(modified, _)| Reverse(*modified));
+        for (_, old) in generations.into_iter().skip(keep_generations - 1) {
+            for compressed in PRECOMPRESSED.map(|encoding| sibling(&old, encoding)) {
+                if compressed.exists() {
+                    fs::remove_file(&compressed)?;
+                }
+            }
+            fs::remove_file(&old)?;
+            println!("  removed: {}", old.display());
+        }
+    }
+
+    Ok(())
+}
+
+// new files first, then the manifest that points at them, then whatever it stopped pointing at.
+// a failure at any step leaves a manifest whose files all exist
+fn commit(paths: &Paths, manifest: &AssetManifest, keep_generations: usize) -> Result<(), Box<dyn std::error::Error>> {
+    publish(paths)?;
+    write_manifest(paths, manifest)?;
+    collect_garbage(paths, manifest, keep_generations)
+}
+
+// readers see either the old or the new content, never a partial write
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let partial = sibling(path, "partial");
+    fs::write(&partial, content)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+fn vendor_scripts(paths: &Paths, manifest: &mut AssetManifest) -> Result<(), Box<dyn std::error::Error>> {
+    let vendor: VendorManifest = toml::from_str(&fs::read_to_string(&paths.vendor)?)?;
+
+    for script in &vendor.scripts {
+        // the pin has to come from the publisher, hashing whatever the first download returned
+        // would trust exactly the file it is meant to check
+        let Some(pinned) = &script.integrity else {
+            return Err(format!(
+                "{} has no integrity in vendor.toml, pin the sha384 the project publishes for {}",
+                script.name, script.url
+            ).into());
+        };
+
+        let content = fetch_script(paths, script)?;
+        let integrity = subresource_integrity(&content);
+        if *pinned != integrity {
+            // drop the cached copy so a corrupted download isn't reused
+            let _ = fs::remove_file(cached_script(paths, script));
+            return Err(format!("{} does not match the pinned integrity {}, got {}", script.url, pinned, integrity).into());
+        }
+
+        let logical = format!("{}.js", script.name);
+        let entry = fingerprint(paths, &logical, &content)?;
+        manifest.insert(logical, entry);
+    }
+
+    Ok(())
+}
+
+fn write_manifest(paths: &Paths, manifest: &AssetManifest) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::to_string_pretty(manifest)? + "\n";
+    let path = paths.manifest();
+
+    let previous = fs::read_to_string(&path).unwrap_or_default();
+    if previous != json {
+        write_atomic(&path, json.as_bytes())?;
+        println!("  updated: {}", path.display());
+    } else {
+        println!("  {} is up to date", path.display());
+    }
+
+    Ok(())
+}
+
+// tailwind writes outside the output directory so nothing half-built is ever served
+fn run_tailwind(cli: &TailwindCli, paths: &Paths) -> Result<String, Box<dyn std::error::Error>> {
+    fs::create_dir_all(&paths.work)?;
+    let output = paths.work.join("computed.css");
+    let result = Command::new(&cli.path)
+        .arg("-i").arg(&paths.input)
+        .arg("-o").arg(&output)
+        .arg("--minify")
+        .current_dir(paths.input.parent().unwrap_or(Path::new(".")))
+        .output()?;
+
+    if !result.status.success() {
         eprintln!("tailwind css failed:");
-        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        eprintln!("{}", String::from_utf8_lossy(&result.stderr));
         return Err("Tailwind CSS build failed".into());
     }
 
-    let css_content = fs::read("static/computed.css")?;
-    let mut hasher = Sha256::new();
-    hasher.update(&css_content);
-    let hash = format!("{:x}", hasher.finalize());
-    let short_hash = &hash[..8];
+    Ok(fs::read_to_string(output)?)
+}
 
-    let old_path = "static/computed.css";
-    let new_filename = format!("computed.{}.css", short_hash);
-    let new_path = format!("static/{}", new_filename);
-    
-    println!("renaming css file to: {}", new_filename);
-    fs::rename(old_path, &new_path)?;
+fn fingerprint_assets(paths: &Paths, assets: &AssetConfig, manifest: &mut AssetManifest) -> Result<(), Box<dyn std::error::Error>> {
+    for name in assets.source_assets(&paths.assets)? {
+        let content = fs::read(paths.assets.join(&name))?;
+        let entry = fingerprint(paths, &name, &content)?;
+        manifest.insert(name, entry);
+    }
 
-    println!("updating html references...");
-    let html_pattern = "server/templates/**/*.html";
-    let css_regex = Regex::new(r#"(?P<before>href=["'])(?P<path>/static/)computed\.(?:[a-f0-9]{8}\.)?css(?P<after>["'])"#)?;
-    let replacement = format!("${{before}}${{path}}{}${{after}}", new_filename);
+    Ok(())
+}
 
-    let mut updated_files = 0;
-    for entry in glob(html_pattern)? {
-        let path = entry?;
-        let content = fs::read_to_string(&path)?;
-        let new_content = css_regex.replace_all(&content, replacement.as_str());
-        
-        if content != new_content {
-            fs::write(&path, new_content.as_ref())?;
-            println!("  updated: {}", path.display());
-            updated_files += 1;
+// printable ascii and whatever else the templates contain, like the box drawing in error.html
+fn used_characters(templates: &Path) -> Result<BTreeSet<char>, Box<dyn std::error::Error>> {
+    let mut characters: BTreeSet<char> = (' '..='~').collect();
+    for entry in glob(&format!("{}/**/*.html", Pattern::escape(&templates.to_string_lossy())))? {
+        characters.extend(fs::read_to_string(entry?)?.chars().filter(|character| !character.is_ascii()));
+    }
+
+    Ok(characters)
+}
+
+// each font in assets.toml cut down to the characters the pages use, fingerprinted as woff2
+fn subset_fonts(paths: &Paths, assets: &AssetConfig, manifest: &mut AssetManifest) -> Result<(), Box<dyn std::error::Error>> {
+    let characters = used_characters(&paths.templates)?;
+    for font in &assets.fonts {
+        let source = paths.assets.join(&font.source);
+        let original = fs::read(&source)?;
+        let woff2 = fonts::subset(&original, &characters)
+            .and_then(|subset| fonts::woff2(&subset))
+            .map_err(|e| format!("{}: {}", source.display(), e))?;
+        println!("  {}: {} bytes -> {} bytes", font.source, original.len(), woff2.len());
+
+        let logical = font.logical();
+        let entry = fingerprint(paths, &logical, &woff2)?;
+        manifest.insert(logical, entry);
+    }
+
+    Ok(())
+}
+
+// returns false without touching the output when the css is the one already in the manifest
+fn fingerprint_css(paths: &Paths, mut css: String, manifest: &mut AssetManifest) -> Result<bool, Box<dyn std::error::Error>> {
+    // the css references fonts by logical path, point it at the hashed copies the page preloads
+    for (name, entry) in manifest.iter() {
+        css = css.replace(&format!("/static/{}", name), &format!("/static/{}", entry.file));
+    }
+
+    let file = hashed_name("computed.css", css.as_bytes())?;
+    if manifest.get("computed.css").is_some_and(|entry| entry.file == file) {
+        return Ok(false);
+    }
+
+    let entry = fingerprint(paths, "computed.css", css.as_bytes())?;
+    manifest.insert("computed.css".to_string(), entry);
+    Ok(true)
+}
+
+fn build(cli: &TailwindCli, paths: &Paths, assets: &AssetConfig) -> Result<AssetManifest, Box<dyn std::error::Error>> {
+    clear_staging(paths)?;
+
+    println!("generating theme css...");
+    generate_theme_css(paths, true)?;
+    println!("generating font css...");
+    generate_font_css(paths, assets, true)?;
+
+    println!("running tailwind css {}...", cli.version);
+    let css = run_tailwind(cli, paths)?;
+
+    let mut manifest = AssetManifest::new();
+
+    println!("fingerprinting assets...");
+    fingerprint_assets(paths, assets, &mut manifest)?;
+    println!("subsetting fonts...");
+    subset_fonts(paths, assets, &mut manifest)?;
+    fingerprint_css(paths, css, &mut manifest)?;
+
+    println!("vendoring scripts...");
+    vendor_scripts(paths, &mut manifest)?;
+
+    // a page pointing at a file that isn't there fails quietly in the browser, fail here instead
+    let unbuilt = unbuilt_references(&paths.templates, &manifest)?;
+    if !unbuilt.is_empty() {
+        clear_staging(paths)?;
+        return Err(unbuilt.join("\n").into());
+    }
+
+    println!("publishing...");
+    commit(paths, &manifest, assets.keep_generations)?;
+
+    Ok(manifest)
+}
+
+// logical names the templates ask for with asset("...") and asset_integrity("...")
+fn template_references(templates: &Path) -> Result<BTreeSet<(PathBuf, String)>, Box<dyn std::error::Error>> {
+    let mut references = BTreeSet::new();
+    for entry in glob(&format!("{}/**/*.html", Pattern::escape(&templates.to_string_lossy())))? {
+        let template = entry?;
+        let source = fs::read_to_string(&template)?;
+        for helper in ["asset(\"", "asset_integrity(\""] {
+            for call in source.split(helper).skip(1) {
+                if let Some((name, _)) = call.split_once('"') {
+                    references.insert((template.clone(), name.to_string()));
+                }
+            }
         }
     }
 
-    if updated_files == 0 {
-        println!("  no html files needed updating");
+    Ok(references)
+}
+
+fn unbuilt_references(templates: &Path, manifest: &AssetManifest) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(template_references(templates)?
+        .into_iter()
+        .filter(|(_, name)| !manifest.contains_key(name))
+        .map(|(template, name)| format!("{} references asset \"{}\", which the build doesn't produce", template.display(), name))
+        .collect())
+}
+
+// builds into a temp dir and compares with the committed output, nothing in the repo is written
+fn check(cli: &TailwindCli, paths: &Paths, assets: &AssetConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let temp = std::env::temp_dir().join(format!("tailwind-check-{}", std::process::id()));
+    let staged = Paths { output: temp.join("output"), work: temp.join("work"), ..paths.clone() };
+    fs::create_dir_all(&staged.output)?;
+
+    let result = compare(cli, paths, &staged, assets);
+    fs::remove_dir_all(&temp)?;
+
+    let stale = result?;
+    if !stale.is_empty() {
+        for problem in &stale {
+            eprintln!("  {}", problem);
+        }
+        return Err(format!("{} is stale, run `cargo run -p tailwind` and commit the result", paths.output.display()).into());
     }
 
-    println!("cleaning up old css files...");
-    let css_pattern = "static/computed.*.css";
-    let mut cleaned_files = 0;
-    
-    for entry in glob(css_pattern)? {
-        let path = entry?;
-        let filename = path.file_name().unwrap().to_str().unwrap();
-        
-        if filename != new_filename {
-            fs::remove_file(&path)?;
-            println!("  removed: {}", filename);
-            cleaned_files += 1;
+    println!("{} is up to date", paths.output.display());
+    Ok(())
+}
+
+fn compare(cli: &TailwindCli, committed: &Paths, staged: &Paths, assets: &AssetConfig) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut stale = Vec::new();
+
+    println!("generating theme css...");
+    if !generate_theme_css(staged, false)? {
+        stale.push(format!("{} differs from {}", committed.themes_css.display(), committed.themes.display()));
+    }
+    println!("generating font css...");
+    if !generate_font_css(staged, assets, false)? {
+        stale.push(format!("{} differs from the fonts in assets.toml", committed.fonts_css.display()));
+    }
+
+    println!("running tailwind css {}...", cli.version);
+    let css = run_tailwind(cli, staged)?;
+
+    let mut fresh = AssetManifest::new();
+    println!("fingerprinting assets...");
+    fingerprint_assets(staged, assets, &mut fresh)?;
+    println!("subsetting fonts...");
+    subset_fonts(staged, assets, &mut fresh)?;
+    fingerprint_css(staged, css, &mut fresh)?;
+    println!("vendoring scripts...");
+    vendor_scripts(staged, &mut fresh)?;
+    publish(staged)?;
+
+    let manifest_path = committed.manifest();
+    let manifest: AssetManifest = fs::read_to_string(&manifest_path)
+        .map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            stale.push(format!("{} can't be read: {}", manifest_path.display(), e));
+            AssetManifest::new()
+        });
+
+    for (name, entry) in &fresh {
+        match manifest.get(name) {
+            Some(committed_entry) if committed_entry == entry => {}
+            Some(committed_entry) => stale.push(format!("{} is {}, the build makes {}", name, committed_entry.file, entry.file)),
+            None => stale.push(format!("{} is missing from {}", name, manifest_path.display())),
+        }
+        if !committed.output.join(&entry.file).exists() {
+            stale.push(format!("{} is not in {}", entry.file, committed.output.display()));
+        }
+    }
+    for name in manifest.keys().filter(|name| !fresh.contains_key(*name)) {
+        stale.push(format!("{} is in {} but no longer built", name, manifest_path.display()));
+    }
+
+    stale.extend(unbuilt_references(&committed.templates, &fresh)?);
+
+    Ok(stale)
+}
+
+// held until the process exits, the os drops it even when the build crashes
+fn lock(paths: &Paths) -> Result<fs::File, Box<dyn std::error::Error>> {
+    fs::create_dir_all(&paths.cache)?;
+    let path = paths.cache.join("tailwind.lock");
+    let file = fs::File::create(&path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => {
+            Err(format!("another tailwind build is running, {} is locked", path.display()).into())
         }
+        Err(fs::TryLockError::Error(e)) => Err(e.into()),
     }
+}
 
-    if cleaned_files == 0 {
-        println!("  no old css files to clean up");
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse()?;
+    let (assets, paths) = AssetConfig::load(&args)?;
+    let _lock = lock(&paths)?;
+    let cli = TailwindCli::resolve(&paths, args.fetch)?;
+
+    if args.check {
+        return check(&cli, &paths, &assets);
     }
 
+    let manifest = build(&cli, &paths, &assets)?;
     println!("done");
 
+    if args.watch {
+        watch::run(&cli, &paths, &assets, manifest)?;
+    }
+
     Ok(())
-} 
\ No newline at end of file
+}
+
+// errors carry instructions (install the cli, pin a checksum), print them readably instead of as Debug
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
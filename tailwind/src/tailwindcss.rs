@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Paths;
+use crate::download;
+
+// the standalone cli pinned in tailwindcss.toml, so every machine builds the same css and hashes
+#[derive(Deserialize)]
+struct TailwindConfig {
+    version: String,
+    // release asset name -> sha256, from sha256sums.txt on the github release
+    #[serde(default)]
+    checksums: BTreeMap<String, String>,
+}
+
+pub struct TailwindCli {
+    pub path: PathBuf,
+    pub version: String,
+}
+
+// release asset names of the standalone cli
+fn release_asset() -> Result<&'static str, Box<dyn std::error::Error>> {
+    match (env::consts::OS, env::consts::ARCH) {
+        ("linux", "x86_64") => Ok("tailwindcss-linux-x64"),
+        ("linux", "aarch64") => Ok("tailwindcss-linux-arm64"),
+        ("macos", "x86_64") => Ok("tailwindcss-macos-x64"),
+        ("macos", "aarch64") => Ok("tailwindcss-macos-arm64"),
+        ("windows", "x86_64") => Ok("tailwindcss-windows-x64.exe"),
+        (os, arch) => Err(format!("tailwindcss has no standalone release for {} {}", os, arch).into()),
+    }
+}
+
+impl TailwindCli {
+    // the pinned version from {cache}/tools, downloaded first with `--fetch`. refuses binaries
+    // whose sha256 isn't the pinned one
+    pub fn resolve(paths: &Paths, fetch: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let config: TailwindConfig = toml::from_str(&fs::read_to_string(&paths.tailwindcss)?)?;
+        let asset = release_asset()?;
+        let release = format!("https://github.com/tailwindlabs/tailwindcss/releases/download/v{}", config.version);
+        let path = paths.cache.join("tools").join(format!("tailwindcss-{}", config.version)).join(asset);
+
+        // checked before anything is downloaded. the pin has to come from the release, a hash of
+        // the binary we happen to have would vouch for exactly the file it is meant to check
+        let Some(pinned) = config.checksums.get(asset) else {
+            return Err(format!(
+                "tailwindcss.toml has no sha256 for {asset}, copy it from\n  {release}/sha256sums.txt\ninto [checksums] as \"{asset}\" = \"...\""
+            ).into());
+        };
+
+        if !path.exists() {
+            if !fetch {
+                return Err(format!(
+                    "tailwindcss {version} is not installed, run `cargo run -p tailwind -- --fetch` or download\n  {release}/{asset}\nto\n  {path}",
+                    version = config.version,
+                    path = path.display(),
+                ).into());
+            }
+            install(&format!("{}/{}", release, asset), &path)?;
+        }
+
+        let checksum = format!("{:x}", Sha256::digest(fs::read(&path)?));
+        if *pinned != checksum {
+            return Err(format!(
+                "{} has sha256 {}, tailwindcss.toml pins {}. delete it and fetch again",
+                path.display(), checksum, pinned
+            ).into());
+        }
+
+        Ok(TailwindCli { path, version: config.version })
+    }
+}
+
+// written next to the target and renamed, an interrupted download never looks installed
+fn install(url: &str, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let content = download(url)?;
+    let partial = path.with_extension("partial");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&partial, content)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&partial, fs::Permissions::from_mode(0o755))?;
+    }
+
+    fs::rename(&partial, path)?;
+    println!("  installed: {}", path.display());
+    Ok(())
+}
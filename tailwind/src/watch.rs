@@ -0,0 +1,130 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+use notify_debouncer_mini::{new_debouncer, notify::RecursiveMode, DebounceEventResult};
+
+use crate::{clear_staging, commit, unbuilt_references, fingerprint_assets, fingerprint_css, generate_font_css, generate_theme_css, run_tailwind, subset_fonts, AssetConfig, AssetManifest, Paths, TailwindCli};
+
+// editors save in bursts (swap file, rename, chmod), one rebuild per burst
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+// what a batch of changed files needs redone besides the css
+#[derive(Debug, Default)]
+struct Changes {
+    themes: bool,
+    assets: bool,
+    // templates decide which characters the font subsets keep
+    fonts: bool,
+}
+
+// the @source globs in input.css plus the inputs the css is generated from, None when nothing relevant changed.
+// inotify also reports reads, so files not modified since the last build are the build's own reads
+fn classify(paths: &Paths, changed: &BTreeSet<PathBuf>, since: SystemTime) -> Option<Changes> {
+    let mut changes = Changes::default();
+    let mut relevant = false;
+
+    for path in changed {
+        if fs::metadata(path).and_then(|metadata| metadata.modified()).is_ok_and(|modified| modified < since) {
+            continue;
+        }
+
+        if *path == paths.themes {
+            changes.themes = true;
+        } else if path.starts_with(&paths.assets) {
+            changes.assets = true;
+            changes.fonts = true;
+        } else if path.starts_with(&paths.templates) {
+            changes.fonts = true;
+        } else if *path != paths.input
+            && !paths.sources.iter().any(|source| path.starts_with(source) && path.extension().is_some_and(|extension| extension == "rs"))
+        {
+            continue;
+        }
+        relevant = true;
+    }
+
+    relevant.then_some(changes)
+}
+
+// works on a copy, the caller keeps the manifest that is on disk until the new one is committed
+fn rebuild(cli: &TailwindCli, paths: &Paths, changes: &Changes, assets: &AssetConfig, manifest: &AssetManifest) -> Result<AssetManifest, Box<dyn std::error::Error>> {
+    let mut manifest = manifest.clone();
+    if changes.themes {
+        generate_theme_css(paths, true)?;
+    }
+    if changes.assets {
+        generate_font_css(paths, assets, true)?;
+        fingerprint_assets(paths, assets, &mut manifest)?;
+    }
+    if changes.fonts {
+        subset_fonts(paths, assets, &mut manifest)?;
+    }
+
+    let css = run_tailwind(cli, paths)?;
+    let changed = fingerprint_css(paths, css, &mut manifest)?;
+
+    // the same rule as the full build. vendored scripts come from it and are still in the manifest
+    let unbuilt = unbuilt_references(&paths.templates, &manifest)?;
+    if !unbuilt.is_empty() {
+        return Err(unbuilt.join("\n").into());
+    }
+
+    if !changed && !changes.assets && !changes.fonts {
+        println!("  css is unchanged");
+        return Ok(manifest);
+    }
+
+    commit(paths, &manifest, assets.keep_generations)?;
+    Ok(manifest)
+}
+
+// rebuilds on every change until interrupted, failed builds are reported and the last good output stays
+pub fn run(cli: &TailwindCli, paths: &Paths, assets: &AssetConfig, mut manifest: AssetManifest) -> Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = mpsc::channel::<DebounceEventResult>();
+    let mut debouncer = new_debouncer(DEBOUNCE, sender)?;
+
+    // single files are watched through their directory, editors replace them on save
+    let files = BTreeSet::from([&paths.input, &paths.themes]);
+    for directory in files.iter().filter_map(|file| file.parent()).collect::<BTreeSet<_>>() {
+        debouncer.watcher().watch(directory, RecursiveMode::NonRecursive)?;
+    }
+    for directory in paths.sources.iter().chain([&paths.templates, &paths.assets]) {
+        debouncer.watcher().watch(directory, RecursiveMode::Recursive)?;
+    }
+
+    let mut last_build = SystemTime::now();
+    println!("watching for changes...");
+    for events in receiver {
+        let changed = match events {
+            Ok(events) => events.into_iter().map(|event| event.path).collect(),
+            Err(e) => {
+                eprintln!("watch error: {}", e);
+                continue;
+            }
+        };
+
+        let Some(changes) = classify(paths, &changed, last_build) else {
+            continue;
+        };
+
+        println!("rebuilding...");
+        last_build = SystemTime::now();
+        match rebuild(cli, paths, &changes, assets, &manifest) {
+            Ok(built) => {
+                manifest = built;
+                println!("done");
+            }
+            Err(e) => {
+                eprintln!("rebuild failed: {}", e);
+                // files staged before the failure would otherwise be published by the next rebuild
+                if let Err(e) = clear_staging(paths) {
+                    eprintln!("clearing staged files failed: {}", e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}